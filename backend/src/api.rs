@@ -2,13 +2,43 @@ use chrono::{DateTime, Utc, Duration};
 use serde::{Deserialize, Serialize};
 use tokio::{io::AsyncWriteExt, net::TcpStream};
 use url::Url;
-use crate::{controller::{SiteState, SITE_CONTROLLERS}, database::DATABASE, server::ConnectionMetadata, Config};
+use crate::{controller::{get_controller_by_name, site_controllers, SiteState, TriggerSource, WakeStats}, database::{percentile, DATABASE}, landing::render_landing_page, reload::reload_config, server::ConnectionMetadata, config::current_config, Config};
 use log::*;
-use std::collections::HashMap;
+use std::{cmp::Reverse, collections::HashMap, sync::LazyLock, time::Instant};
 use sha2::{Sha256, Digest};
 
-/// Helper function to send a JSON response
-async fn send_json_response(mut stream: TcpStream, data: &impl Serialize) -> Result<(), ()> {
+/// When the hibernator started, forced at startup
+pub static STARTED_AT: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Connection of an API client, along with how it wants errors to be formatted
+pub struct ApiStream {
+    stream: TcpStream,
+    json_errors: bool,
+}
+
+#[derive(Serialize)]
+struct ApiError<'a> {
+    error: &'a str,
+    status: u16,
+}
+
+/// Helper function to send a JSON response, which clients must not cache
+async fn send_json_response(stream: ApiStream, data: &impl Serialize) -> Result<(), ()> {
+    send_json_response_with_headers(stream, data, "Cache-Control: no-store\r\n").await
+}
+
+/// Same as [`send_json_response`], but clients may cache the response for `api_cache_max_age` if it's set.
+/// Meant for expensive endpoints polled by dashboards.
+async fn send_cacheable_json_response(stream: ApiStream, data: &impl Serialize) -> Result<(), ()> {
+    let headers = match current_config().top_level.api_cache_max_age {
+        Some(max_age) if max_age > 0 => format!("Cache-Control: private, max-age={max_age}\r\n"),
+        _ => String::from("Cache-Control: no-store\r\n"),
+    };
+    send_json_response_with_headers(stream, data, &headers).await
+}
+
+/// Same as [`send_json_response`], with additional headers, each ending with `\r\n`
+async fn send_json_response_with_headers(mut stream: ApiStream, data: &impl Serialize, headers: &str) -> Result<(), ()> {
     let content = match serde_json::to_string(data) {
         Ok(content) => content,
         Err(e) => {
@@ -20,22 +50,35 @@ async fn send_json_response(mut stream: TcpStream, data: &impl Serialize) -> Res
 
     let status_line = "HTTP/1.1 200 OK";
     let length = content.len();
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\nContent-Type: application/json\r\n\r\n{content}");
-    let _ = stream.write_all(response.as_bytes()).await;
+    let response = format!("{status_line}\r\nConnection: close\r\nContent-Length: {length}\r\nContent-Type: application/json\r\n{headers}\r\n{content}");
+    let _ = stream.stream.write_all(response.as_bytes()).await;
+    let _ = stream.stream.shutdown().await;
     Ok(())
 }
 
-/// Helper function to send an error response
-async fn send_error_response(mut stream: TcpStream, status_code: u16, message: &str) {
+/// Helper function to send an error response.
+/// The error is sent as JSON if the client accepts it, and as plain text otherwise.
+async fn send_error_response(mut stream: ApiStream, status_code: u16, message: &str) {
     let status_line = match status_code {
+        400 => "HTTP/1.1 400 Bad Request",
         401 => "HTTP/1.1 401 Unauthorized",
         404 => "HTTP/1.1 404 Not Found",
+        405 => "HTTP/1.1 405 Method Not Allowed",
+        409 => "HTTP/1.1 409 Conflict",
         500 => "HTTP/1.1 500 Internal Server Error",
         _ => "HTTP/1.1 500 Internal Server Error",
     };
-    let length = message.len();
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{message}");
-    let _ = stream.write_all(response.as_bytes()).await;
+    let (content_type, content) = match stream.json_errors {
+        true => {
+            let error = ApiError { error: message, status: status_code };
+            ("application/json", serde_json::to_string(&error).unwrap_or_default())
+        }
+        false => ("text/plain", message.to_string()),
+    };
+    let length = content.len();
+    let response = format!("{status_line}\r\nConnection: close\r\nCache-Control: no-store\r\nContent-Length: {length}\r\nContent-Type: {content_type}\r\n\r\n{content}");
+    let _ = stream.stream.write_all(response.as_bytes()).await;
+    let _ = stream.stream.shutdown().await;
 }
 
 /// Check if the provided API key is valid
@@ -61,22 +104,64 @@ fn check_api_key(config: &Config, provided_key: Option<&str>) -> bool {
     provided_hash == *expected_hash
 }
 
-/// Handle API requests with authentication
+/// Check if the provided bearer token matches the configured one
+fn check_api_token(config: &Config, provided_token: Option<&str>) -> bool {
+    let (Some(expected_token), Some(provided_token)) = (&config.top_level.api_token, provided_token) else {
+        return false;
+    };
+
+    // Compare hashes so that the comparison time doesn't depend on the token
+    Sha256::digest(expected_token.as_bytes()) == Sha256::digest(provided_token.as_bytes())
+}
+
+/// Check if the request is allowed to use the API.
+/// When credentials are configured, any of them is accepted.
+fn is_authorized(config: &Config, provided_key: Option<&str>, provided_token: Option<&str>) -> bool {
+    match (&config.top_level.api_key_sha256, &config.top_level.api_token) {
+        (None, None) => true,
+        (Some(_), None) => check_api_key(config, provided_key),
+        (None, Some(_)) => check_api_token(config, provided_token),
+        (Some(_), Some(_)) => check_api_key(config, provided_key) || check_api_token(config, provided_token),
+    }
+}
+
+/// Handle API requests with authentication.
+/// The path is relative to the configured `api_prefix`.
 pub async fn handle_api_request(
     stream: TcpStream,
     http_request: &[String],
     path: &str,
     config: &'static Config,
 ) -> bool {
+    let method = http_request
+        .first()
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap_or("GET");
+
     // Extract API key from headers
     let api_key = http_request
         .iter()
         .find(|line| line.to_lowercase().starts_with("x-api-key: "))
         .map(|line| &line[11..]);
 
-    // Check authentication
-    if !check_api_key(config, api_key) {
-        send_error_response(stream, 401, "Unauthorized: Invalid or missing API key").await;
+    // Extract bearer token from headers
+    let api_token = http_request
+        .iter()
+        .find(|line| line.to_lowercase().starts_with("authorization: bearer "))
+        .map(|line| line[22..].trim());
+
+    // Send errors as JSON to clients that ask for it
+    let json_errors = http_request
+        .iter()
+        .find(|line| line.to_lowercase().starts_with("accept:"))
+        .is_some_and(|line| line.to_lowercase().contains("application/json"));
+    let stream = ApiStream { stream, json_errors };
+
+    // Check authentication, except for liveness probes and the progress polled by landing pages
+    let route = path.split('?').next().unwrap_or_default();
+    let is_public = route == "/healthz" || (route.starts_with("/services/") && route.ends_with("/progress"));
+    if !is_public && !is_authorized(config, api_key, api_token) {
+        send_error_response(stream, 401, "Unauthorized: Invalid or missing API key or token").await;
         return true;
     }
 
@@ -93,37 +178,102 @@ pub async fn handle_api_request(
     let segments: Vec<_> = url.path_segments().map(|c| c.collect()).unwrap_or_default();
 
     // GET /hibernator-api/services
-    if segments.len() == 2 && segments[0] == "hibernator-api" && segments[1] == "services" {
+    if segments.len() == 1 && segments[0] == "services" {
         handle_services_request(stream).await;
         return true;
     }
 
+    // GET /hibernator-api/healthz
+    if segments.len() == 1 && segments[0] == "healthz" {
+        handle_healthz_request(stream).await;
+        return true;
+    }
+
+    // GET /hibernator-api/status
+    if segments.len() == 1 && segments[0] == "status" {
+        handle_status_request(stream).await;
+        return true;
+    }
+
     // GET /hibernator-api/services/:name/config
-    if segments.len() == 4 && segments[0] == "hibernator-api" && segments[1] == "services" && segments[3] == "config" {
-        let service_name = segments[2];
+    if segments.len() == 3 && segments[0] == "services" && segments[2] == "config" {
+        let service_name = segments[1];
         handle_service_config_request(stream, service_name).await;
         return true;
     }
 
     // GET /hibernator-api/services/:name/metrics
-    if segments.len() == 4 && segments[0] == "hibernator-api" && segments[1] == "services" && segments[3] == "metrics" {
-        let service_name = segments[2];
+    if segments.len() == 3 && segments[0] == "services" && segments[2] == "metrics" {
+        let service_name = segments[1];
         handle_metrics_request(stream, service_name, &url).await;
         return true;
     }
 
+    // GET /hibernator-api/services/:name/progress
+    if segments.len() == 3 && segments[0] == "services" && segments[2] == "progress" {
+        let service_name = segments[1];
+        handle_progress_request(stream, service_name).await;
+        return true;
+    }
+
+    // GET /hibernator-api/services/:name/start-durations
+    if segments.len() == 3 && segments[0] == "services" && segments[2] == "start-durations" {
+        let service_name = segments[1];
+        handle_start_durations_request(stream, service_name).await;
+        return true;
+    }
+
+    // GET /hibernator-api/services/:name/landing-preview
+    if segments.len() == 3 && segments[0] == "services" && segments[2] == "landing-preview" {
+        let service_name = segments[1];
+        handle_landing_preview_request(stream, service_name, config).await;
+        return true;
+    }
+
+    // POST /hibernator-api/services/:name/wake
+    if segments.len() == 3 && segments[0] == "services" && segments[2] == "wake" {
+        if method != "POST" {
+            send_error_response(stream, 405, "Wake must be requested with POST").await;
+            return true;
+        }
+        let service_name = segments[1];
+        handle_wake_request(stream, service_name).await;
+        return true;
+    }
+
+    // DELETE /hibernator-api/services/:name/history
+    if segments.len() == 3 && segments[0] == "services" && segments[2] == "history" {
+        if method != "DELETE" {
+            send_error_response(stream, 405, "History must be deleted with DELETE").await;
+            return true;
+        }
+        let service_name = segments[1];
+        handle_delete_history_request(stream, service_name, &url).await;
+        return true;
+    }
+
     // GET /hibernator-api/history
-    if segments.len() == 2 && segments[0] == "hibernator-api" && segments[1] == "history" {
+    if segments.len() == 1 && segments[0] == "history" {
         handle_history_request(stream, &url).await;
         return true;
     }
 
     // GET /hibernator-api/state-history
-    if segments.len() == 2 && segments[0] == "hibernator-api" && segments[1] == "state-history" {
+    if segments.len() == 1 && segments[0] == "state-history" {
         handle_state_history_request(stream, &url).await;
         return true;
     }
 
+    // POST /hibernator-api/reload
+    if segments.len() == 1 && segments[0] == "reload" {
+        if method != "POST" {
+            send_error_response(stream, 405, "Reload must be requested with POST").await;
+            return true;
+        }
+        handle_reload_request(stream).await;
+        return true;
+    }
+
     // No matching endpoint
     send_error_response(stream, 404, "API endpoint not found").await;
     true
@@ -144,12 +294,86 @@ pub struct StateHistoryEntry {
     pub end_time: DateTime<Utc>,
     pub service: String,
     pub state: String,
+    pub trigger_source: Option<String>, // What triggered the start, for starting states
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ServiceInfo {
     pub name: String,
+    pub state: String, // "disabled" for sites that aren't managed
+    pub enabled: bool,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub last_changed: DateTime<Utc>,
+    pub failed_starts: u32,
+    #[serde(with = "chrono::serde::ts_seconds_option")]
+    pub start_backoff_until: Option<DateTime<Utc>>,
+    pub nginx_error: Option<String>,
+    pub parse_error: Option<String>, // Set while the access log can't be parsed, which keeps the site up
+    pub access_log_error: Option<String>, // Set while the access log is missing or unreadable, which keeps the site up
+    pub progress_done_ms: Option<u64>, // Same progress as shown on the landing page, null when ETA is disabled
+    pub progress_total_ms: Option<u64>,
+    pub total_wakes: u64,
+    pub total_hibernations: u64,
+    #[serde(with = "chrono::serde::ts_seconds_option")]
+    pub last_wake: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StartDurations {
+    pub name: String,
+    pub samples_ms: Vec<u64>, // Newest first, at most eta_sample_size of them
+    pub count: usize,
+    pub min_ms: Option<u64>,
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+    pub max_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProgressResponse {
     pub state: String,
+    pub done_ms: Option<u64>, // Null when ETA is disabled or unknown
+    pub total_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Healthz {
+    pub ok: bool,
+    pub sites: usize,
+    pub uptime_s: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SleepingSite {
+    pub name: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub since: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FleetStatus {
+    pub sites: usize,
+    pub up: usize,
+    pub down: usize,
+    pub starting: usize,
+    pub stopping: usize,
+    pub unknown: usize,
+    pub disabled: usize,
+    pub memory_saved_mb: u64, // Sum of memory_estimate_mb of the sites that are down
+    pub oldest_sleeping: Option<SleepingSite>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteHistoryResponse {
+    pub name: String,
+    pub deleted_connections: usize,
+    pub deleted_states: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WakeResponse {
+    pub name: String,
+    pub state: String, // State when the wake was requested, the start happens in the background
     #[serde(with = "chrono::serde::ts_seconds")]
     pub last_changed: DateTime<Utc>,
 }
@@ -161,39 +385,157 @@ pub struct ServiceMetrics {
     pub total_hibernations: usize,
     pub start_times_histogram: Vec<u64>, // Buckets of start times in milliseconds
     pub start_duration_estimate_ms: Option<u64>, // From get_start_duration_estimate
+    pub cold_request_ratio: Option<f64>, // Share of requests that arrived while the site wasn't up
+    pub state_durations: HashMap<String, f64>, // Seconds spent in each state over the window
+    pub result_counts: HashMap<String, u64>, // Number of requests by result over the window
+    pub wake_stats: WakeStats, // Request coalescing since the hibernator started, regardless of the window
 }
 
-pub async fn handle_services_request(stream: TcpStream) {
-    // SAFETY: This is safe because SITE_CONTROLLERS is only mutated once during initialization
-    #[allow(static_mut_refs)]
-    let services: Vec<ServiceInfo> = unsafe {
-        SITE_CONTROLLERS.iter().map(|controller| {
-            let (state, last_changed) = controller.get_state_with_last_changed();
-            let state_str = match state {
-                crate::controller::SiteState::Unknown => "unknown",
-                crate::controller::SiteState::Down => "down",
-                crate::controller::SiteState::Up => "up",
-                crate::controller::SiteState::Starting => "starting",
-            };
-            ServiceInfo {
-                name: controller.config.name.to_string(),
-                state: state_str.to_string(),
-                last_changed
-            }
-        }).collect()
-    };
+pub async fn handle_services_request(stream: ApiStream) {
+    let mut services = Vec::new();
+    for controller in site_controllers().iter() {
+        let (state, last_changed) = controller.get_state_with_last_changed();
+        let progress = controller.get_progress().await;
+        let counters = controller.counters();
+        services.push(ServiceInfo {
+            name: controller.config.name.to_string(),
+            state: match controller.config.enabled() {
+                true => state.as_str().to_string(),
+                false => String::from("disabled"),
+            },
+            enabled: controller.config.enabled(),
+            last_changed,
+            failed_starts: controller.failed_starts(),
+            start_backoff_until: controller.start_backoff_until(),
+            nginx_error: controller.nginx_error(),
+            parse_error: controller.parse_error(),
+            access_log_error: controller.access_log_error(),
+            progress_done_ms: progress.map(|(done, _)| done.as_millis() as u64),
+            progress_total_ms: progress.map(|(_, duration)| duration.as_millis() as u64),
+            total_wakes: counters.total_wakes,
+            total_hibernations: counters.total_hibernations,
+            last_wake: counters.last_wake,
+        });
+    }
 
     let _ = send_json_response(stream, &services).await;
 }
 
-pub async fn handle_service_config_request(stream: TcpStream, service_name: &str) {
-    trace!("Handling service config request for: {}", service_name);
+pub async fn handle_start_durations_request(stream: ApiStream, service_name: &str) {
+    trace!("Handling start durations request for: {}", service_name);
+
+    let Some(controller) = get_controller_by_name(service_name) else {
+        send_error_response(stream, 404, &format!("Service '{}' not found", service_name)).await;
+        return;
+    };
 
-    // SAFETY: This is safe because SITE_CONTROLLERS is only mutated once during initialization
-    #[allow(static_mut_refs)]
-    let controller = unsafe {
-        SITE_CONTROLLERS.iter().find(|controller| controller.config.name == service_name)
+    let samples = match DATABASE.get_start_durations(service_name, controller.config.eta_sample_size.0) {
+        Ok(samples) => samples,
+        Err(e) => {
+            error!("Failed to get start durations of {}: {}", service_name, e);
+            send_error_response(stream, 500, "Failed to get start durations").await;
+            return;
+        }
     };
+    let samples_ms: Vec<u64> = samples.iter().map(|d| d.as_millis() as u64).collect();
+
+    let mut sorted = samples_ms.clone();
+    sorted.sort();
+
+    let response = StartDurations {
+        name: service_name.to_string(),
+        count: samples_ms.len(),
+        min_ms: sorted.first().copied(),
+        p50_ms: percentile(&sorted, 50),
+        p95_ms: percentile(&sorted, 95),
+        max_ms: sorted.last().copied(),
+        samples_ms,
+    };
+    let _ = send_json_response(stream, &response).await;
+}
+
+/// The URL landing pages poll to follow the progress of a site, if the API is enabled
+pub fn progress_url(config: &Config, service_name: &str) -> Option<String> {
+    if !config.top_level.api_enabled() {
+        return None;
+    }
+    let service_name: String = url::form_urlencoded::byte_serialize(service_name.as_bytes()).collect();
+    Some(format!("{}/services/{service_name}/progress", config.top_level.api_prefix()))
+}
+
+/// Progress of a site, polled by its landing page.
+/// It's the same information as on the landing page, so it doesn't require authentication.
+pub async fn handle_progress_request(stream: ApiStream, service_name: &str) {
+    let Some(controller) = get_controller_by_name(service_name) else {
+        send_error_response(stream, 404, &format!("Service '{}' not found", service_name)).await;
+        return;
+    };
+
+    let progress = controller.get_progress().await;
+    let response = ProgressResponse {
+        state: controller.get_state().as_str().to_string(),
+        done_ms: progress.map(|(done, _)| done.as_millis() as u64),
+        total_ms: progress.map(|(_, duration)| duration.as_millis() as u64),
+    };
+    let _ = send_json_response(stream, &response).await;
+}
+
+/// Liveness of the hibernator itself, which doesn't touch the database nor the sites
+pub async fn handle_healthz_request(stream: ApiStream) {
+    let healthz = Healthz {
+        ok: true,
+        sites: site_controllers().len(),
+        uptime_s: STARTED_AT.elapsed().as_secs(),
+    };
+    let _ = send_json_response(stream, &healthz).await;
+}
+
+pub async fn handle_status_request(stream: ApiStream) {
+    let controllers = site_controllers();
+    let mut status = FleetStatus {
+        sites: controllers.len(),
+        up: 0,
+        down: 0,
+        starting: 0,
+        stopping: 0,
+        unknown: 0,
+        disabled: 0,
+        memory_saved_mb: 0,
+        oldest_sleeping: None,
+    };
+
+    for controller in controllers.iter() {
+        if !controller.config.enabled() {
+            status.disabled += 1;
+            continue;
+        }
+
+        let (state, last_changed) = controller.get_state_with_last_changed();
+        match state {
+            SiteState::Up => status.up += 1,
+            SiteState::Starting => status.starting += 1,
+            SiteState::Stopping => status.stopping += 1,
+            SiteState::Unknown => status.unknown += 1,
+            SiteState::Down => {
+                status.down += 1;
+                status.memory_saved_mb += controller.config.memory_estimate_mb.unwrap_or(0);
+                if status.oldest_sleeping.as_ref().is_none_or(|oldest| last_changed < oldest.since) {
+                    status.oldest_sleeping = Some(SleepingSite {
+                        name: controller.config.name.to_string(),
+                        since: last_changed,
+                    });
+                }
+            },
+        }
+    }
+
+    let _ = send_json_response(stream, &status).await;
+}
+
+pub async fn handle_service_config_request(stream: ApiStream, service_name: &str) {
+    trace!("Handling service config request for: {}", service_name);
+
+    let controller = get_controller_by_name(service_name);
 
     let controller = match controller {
         Some(controller) => controller,
@@ -206,7 +548,7 @@ pub async fn handle_service_config_request(stream: TcpStream, service_name: &str
     let _ = send_json_response(stream, &controller.config).await;
 }
 
-pub async fn handle_history_request(stream: TcpStream, url: &Url) {
+pub async fn handle_history_request(stream: ApiStream, url: &Url) {
     trace!("Handling history request: {}", url);
 
     let query_pairs: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
@@ -237,7 +579,7 @@ pub async fn handle_history_request(stream: TcpStream, url: &Url) {
     let _ = send_json_response(stream, &entries).await;
 }
 
-pub async fn handle_state_history_request(stream: TcpStream, url: &Url) {
+pub async fn handle_state_history_request(stream: ApiStream, url: &Url) {
     trace!("Handling state history request: {}", url);
 
     let query_pairs: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
@@ -264,14 +606,10 @@ pub async fn handle_state_history_request(stream: TcpStream, url: &Url) {
             }
         };
         
-        all_ranges = ranges;
+        all_ranges = ranges.into_iter().map(|range| (svc.to_string(), range)).collect();
     } else {
         // Query all services and collect results
-        // SAFETY: This is safe because SITE_CONTROLLERS is only mutated once during initialization
-        #[allow(static_mut_refs)]
-        let services: Vec<&str> = unsafe {
-            SITE_CONTROLLERS.iter().map(|controller| controller.config.name.as_str()).collect()
-        };
+        let services: Vec<&str> = site_controllers().iter().map(|controller| controller.config.name.as_str()).collect();
 
         for svc in services {
             let ranges = match DATABASE.get_state_history(
@@ -287,36 +625,35 @@ pub async fn handle_state_history_request(stream: TcpStream, url: &Url) {
                 }
             };
             
-            all_ranges.extend(ranges);
+            all_ranges.extend(ranges.into_iter().map(|range| (svc.to_string(), range)));
         }
 
         // Sort by start_time (newest first since we're querying backwards)
-        all_ranges.sort_by(|a, b| b.0.cmp(&a.0));
+        all_ranges.sort_by_key(|(_, range)| Reverse(range.0));
         
         // Limit to min_results
         all_ranges.truncate(min_results);
     }
 
     // Convert to API format
-    let entries: Vec<StateHistoryEntry> = all_ranges.into_iter().map(|(start_time, end_time, state)| {
-        let state_str = match state {
-            SiteState::Unknown => "unknown",
-            SiteState::Down => "down",
-            SiteState::Up => "up",
-            SiteState::Starting => "starting",
+    let entries: Vec<StateHistoryEntry> = all_ranges.into_iter().map(|(service, (start_time, end_time, state))| {
+        let trigger_source = match state {
+            SiteState::Starting => DATABASE.get_trigger_source(&service, start_time).ok().flatten(),
+            _ => None,
         };
         StateHistoryEntry {
             start_time,
             end_time,
-            service: service.unwrap_or("unknown").to_string(),
-            state: state_str.to_string(),
+            service,
+            state: state.as_str().to_string(),
+            trigger_source: trigger_source.map(|source| source.as_str().to_string()),
         }
     }).collect();
 
-    let _ = send_json_response(stream, &entries).await;
+    let _ = send_cacheable_json_response(stream, &entries).await;
 }
 
-pub async fn handle_metrics_request(stream: TcpStream, service_name: &str, url: &Url) {
+pub async fn handle_metrics_request(stream: ApiStream, service_name: &str, url: &Url) {
     trace!("Handling metrics request for: {}", service_name);
 
     // Parse the 'seconds' query parameter (default to 86400 = 24 hours)
@@ -326,11 +663,7 @@ pub async fn handle_metrics_request(stream: TcpStream, service_name: &str, url:
         .and_then(|s| s.parse::<i64>().ok())
         .unwrap_or(86400);
 
-    // SAFETY: This is safe because SITE_CONTROLLERS is only mutated once during initialization
-    #[allow(static_mut_refs)]
-    let controller = unsafe {
-        SITE_CONTROLLERS.iter().find(|controller| controller.config.name == service_name)
-    };
+    let controller = get_controller_by_name(service_name);
 
     let controller = match controller {
         Some(controller) => controller,
@@ -373,7 +706,7 @@ pub async fn handle_metrics_request(stream: TcpStream, service_name: &str, url:
 
         match (state1, state2) {
             (SiteState::Unknown, _) | (_, SiteState::Unknown) => (),
-            (SiteState::Down | SiteState::Starting, SiteState::Down | SiteState::Starting) => {
+            (SiteState::Down | SiteState::Starting, SiteState::Down | SiteState::Starting | SiteState::Stopping) => {
                 // Stayed down
                 total_available_ms += duration_ms;
             },
@@ -386,7 +719,7 @@ pub async fn handle_metrics_request(stream: TcpStream, service_name: &str, url:
                     start_durations_ms.push(duration_ms);
                 }
             },
-            (SiteState::Up, SiteState::Down | SiteState::Starting) => {
+            (SiteState::Up, SiteState::Down | SiteState::Starting | SiteState::Stopping) => {
                 // Went down
                 total_available_ms += duration_ms;
                 total_uptime_ms += duration_ms;
@@ -396,6 +729,11 @@ pub async fn handle_metrics_request(stream: TcpStream, service_name: &str, url:
                 // Stayed up
                 total_available_ms += duration_ms;
                 total_uptime_ms += duration_ms;
+            },
+            (SiteState::Stopping, _) => {
+                // Still running while stopping
+                total_available_ms += duration_ms;
+                total_uptime_ms += duration_ms;
             }
         }
     }
@@ -412,6 +750,11 @@ pub async fn handle_metrics_request(stream: TcpStream, service_name: &str, url:
         0.0
     };
 
+    let state_durations = state_durations_ms(&state_history)
+        .into_iter()
+        .map(|(state, duration_ms)| (state.as_str().to_string(), duration_ms as f64 / 1000.0))
+        .collect();
+
     // Create histogram with buckets (0-1s, 1-5s, 5-10s, 10-30s, 30s+)
     let histogram = vec![
         start_durations_ms.iter().filter(|&&d| d < 1000).count() as u64,
@@ -423,17 +766,322 @@ pub async fn handle_metrics_request(stream: TcpStream, service_name: &str, url:
 
     // Get start duration estimate from database
     let start_duration_estimate_ms = DATABASE
-        .get_start_duration_estimate(service_name, controller.config.eta_percentile.0)
+        .get_start_duration_estimate(service_name, controller.config.eta_sample_size.0, controller.config.eta_percentile.0)
         .ok()
         .map(|d| d.as_millis() as u64);
 
+    // Get the share of requests that had to wait for the site to start
+    let connections = match DATABASE.get_service_connections_since(service_name, since.timestamp().max(0) as u64) {
+        Ok(connections) => connections,
+        Err(e) => {
+            error!("Error fetching request history: {}", e);
+            send_error_response(stream, 500, &format!("Error fetching metrics: {}", e)).await;
+            return;
+        }
+    };
+    let cold_request_ratio = cold_request_ratio(&connections);
+
+    let mut result_counts: HashMap<String, u64> = HashMap::new();
+    for (_, metadata) in &connections {
+        *result_counts.entry(metadata.result.as_str().to_string()).or_default() += 1;
+    }
+
     let metrics = ServiceMetrics {
         hibernating_percentage,
         available_percentage,
         total_hibernations,
         start_times_histogram: histogram,
         start_duration_estimate_ms,
+        cold_request_ratio,
+        state_durations,
+        result_counts,
+        wake_stats: controller.wake_stats(),
+    };
+
+    let _ = send_cacheable_json_response(stream, &metrics).await;
+}
+
+/// Time spent in each state, in milliseconds, over a state history ending with the current time
+fn state_durations_ms(state_history: &[(DateTime<Utc>, SiteState)]) -> HashMap<SiteState, u64> {
+    let mut state_durations_ms = HashMap::new();
+    for window in state_history.windows(2) {
+        let ((timestamp1, state), (timestamp2, _)) = (window[0], window[1]);
+        *state_durations_ms.entry(state).or_default() += (timestamp2 - timestamp1).num_milliseconds().max(0) as u64;
+    }
+    state_durations_ms
+}
+
+/// Share of the requests that arrived while the site wasn't up, if there were any
+fn cold_request_ratio(connections: &[(u64, ConnectionMetadata)]) -> Option<f64> {
+    let cold_requests = connections.iter().filter(|(_, metadata)| metadata.cold).count();
+    match connections.len() {
+        0 => None,
+        total => Some(cold_requests as f64 / total as f64),
+    }
+}
+
+pub async fn handle_wake_request(stream: ApiStream, service_name: &str) {
+    trace!("Handling wake request for: {}", service_name);
+
+    let Some(controller) = get_controller_by_name(service_name) else {
+        send_error_response(stream, 404, &format!("Service '{}' not found", service_name)).await;
+        return;
+    };
+
+    if !controller.config.enabled() {
+        send_error_response(stream, 409, &format!("Service '{}' is disabled", service_name)).await;
+        return;
+    }
+
+    controller.trigger_start(TriggerSource::Api);
+
+    let (state, last_changed) = controller.get_state_with_last_changed();
+    let response = WakeResponse {
+        name: controller.config.name.to_string(),
+        state: state.as_str().to_string(),
+        last_changed,
+    };
+    let _ = send_json_response(stream, &response).await;
+}
+
+pub async fn handle_delete_history_request(stream: ApiStream, service_name: &str, url: &Url) {
+    trace!("Handling history deletion for: {}", service_name);
+
+    let query_pairs: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    let delete_states = query_pairs.get("states").is_some_and(|s| s == "true");
+
+    // Sites that were removed from the config can still be purged
+    let deleted_connections = match DATABASE.delete_connection_history(service_name) {
+        Ok(deleted) => deleted,
+        Err(e) => {
+            error!("Failed to delete request history of {}: {}", service_name, e);
+            send_error_response(stream, 500, "Failed to delete request history").await;
+            return;
+        }
+    };
+
+    let mut deleted_states = 0;
+    if delete_states {
+        deleted_states = match DATABASE.delete_state_history(service_name) {
+            Ok(deleted) => deleted,
+            Err(e) => {
+                error!("Failed to delete state history of {}: {}", service_name, e);
+                send_error_response(stream, 500, "Failed to delete state history").await;
+                return;
+            }
+        };
+    }
+
+    info!("Deleted history of {service_name}: {deleted_connections} requests, {deleted_states} state changes");
+
+    let response = DeleteHistoryResponse {
+        name: service_name.to_string(),
+        deleted_connections,
+        deleted_states,
+    };
+    let _ = send_json_response(stream, &response).await;
+}
+
+pub async fn handle_reload_request(stream: ApiStream) {
+    trace!("Handling reload request");
+
+    match reload_config().await {
+        Ok(summary) => {
+            let _ = send_json_response(stream, &summary).await;
+        }
+        Err(e) => {
+            error!("Failed to reload config: {}", e);
+            send_error_response(stream, 500, &format!("Failed to reload config: {}", e)).await;
+        }
+    }
+}
+
+pub async fn handle_landing_preview_request(mut stream: ApiStream, service_name: &str, config: &Config) {
+    trace!("Handling landing preview request for: {}", service_name);
+
+    let controller = match get_controller_by_name(service_name) {
+        Some(controller) => controller,
+        None => {
+            send_error_response(stream, 404, &format!("Service '{}' not found", service_name)).await;
+            return;
+        }
     };
 
-    let _ = send_json_response(stream, &metrics).await;
+    // Use the current progress when available, or sample values otherwise
+    let (done, duration) = match controller.get_progress().await {
+        Some((done, duration)) if !duration.is_zero() => (done, duration),
+        _ => (std::time::Duration::from_secs(3), std::time::Duration::from_secs(10)),
+    };
+
+    let landing_folder = controller.config.landing_folder(config);
+    let content = match render_landing_page(landing_folder, &controller.config.name, done, duration, controller.config.keep_alive, None).await {
+        Ok(content) => content,
+        Err(e) => {
+            error!("Could not render landing page of {}: {}", service_name, e);
+            send_error_response(stream, 500, &format!("Could not render landing page: {}", e)).await;
+            return;
+        }
+    };
+
+    let status_line = "HTTP/1.1 200 OK";
+    let length = content.len();
+    let response = format!("{status_line}\r\nConnection: close\r\nCache-Control: no-store\r\nContent-Length: {length}\r\nContent-Type: text/html; charset=utf-8\r\n\r\n{content}");
+    let _ = stream.stream.write_all(response.as_bytes()).await;
+    let _ = stream.stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::TEST_CONFIG_PATH, server::ConnectionResult, test_utils::*};
+    use super::*;
+
+    fn connection(cold: bool) -> (u64, ConnectionMetadata) {
+        let metadata = serde_json::json!({
+            "request": [], "result": ConnectionResult::ProxySuccess, "service": "site", "is_browser": false,
+            "real_ip": null, "method": "GET", "url": "/", "cold": cold,
+        });
+        (0, serde_json::from_value(metadata).unwrap())
+    }
+
+    #[test]
+    fn state_durations_add_up_to_the_window() {
+        let start = Utc::now();
+        let at = |seconds: i64| start + Duration::seconds(seconds);
+        let history = [
+            (at(0), SiteState::Down),
+            (at(10), SiteState::Starting),
+            (at(15), SiteState::Up),
+            (at(75), SiteState::Stopping),
+            (at(77), SiteState::Down),
+            (at(100), SiteState::Down),
+        ];
+
+        let durations = state_durations_ms(&history);
+        assert_eq!(durations[&SiteState::Down], 33_000);
+        assert_eq!(durations[&SiteState::Starting], 5_000);
+        assert_eq!(durations[&SiteState::Up], 60_000);
+        assert_eq!(durations[&SiteState::Stopping], 2_000);
+        assert_eq!(durations.values().sum::<u64>(), 100_000);
+        assert!(state_durations_ms(&history[..1]).is_empty());
+    }
+
+    #[test]
+    fn cold_request_ratio_counts_requests_made_while_down() {
+        assert_eq!(cold_request_ratio(&[]), None);
+        assert_eq!(cold_request_ratio(&[connection(false), connection(false)]), Some(0.0));
+        assert_eq!(cold_request_ratio(&[connection(true), connection(false), connection(false), connection(false)]), Some(0.25));
+        assert_eq!(cold_request_ratio(&[connection(true)]), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn reload_makes_added_sites_routable() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let (first_port, _) = upstream(ok_response("first")).await;
+        let (added_port, _) = upstream(ok_response("added")).await;
+        let config = env.load("", vec![env.site("reload-first", first_port, "")]);
+        let port = start_hibernator(config).await;
+
+        let response = get(port, "reload-added.test", "/", &[]).await;
+        assert_eq!(response.status, 404);
+
+        let config_path = env.write_config("", vec![env.site("reload-first", first_port, ""), env.site("reload-added", added_port, "")]);
+        *TEST_CONFIG_PATH.write().unwrap() = Some(config_path);
+        let response = Response::parse(&send_raw(port, b"POST /hibernator-api/reload HTTP/1.1\r\nHost: localhost\r\n\r\n").await);
+        assert_eq!(response.status, 200);
+        let summary: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(summary["added"], serde_json::json!(["reload-added"]));
+
+        let response = get(port, "reload-added.test", "/", &[]).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "added");
+    }
+
+    #[tokio::test]
+    async fn landing_preview_is_rendered_for_the_site() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let port = start_hibernator(env.load("", vec![env.site("preview", free_port(), "keep_alive = \"2h\"")])).await;
+
+        let response = get(port, "localhost", "/hibernator-api/services/preview/landing-preview", &[]).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+        // Sites without start history get sample progress values
+        assert_eq!(response.text(), "<h1>preview</h1><p>7200 3000 10000 null</p>");
+    }
+
+    #[tokio::test]
+    async fn errors_are_json_for_clients_accepting_it() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let port = start_hibernator(env.load("", vec![env.site("known", free_port(), "")])).await;
+
+        let response = get(port, "localhost", "/hibernator-api/services/unknown/config", &["Accept: application/json"]).await;
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        let error: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(error, serde_json::json!({"error": "Service 'unknown' not found", "status": 404}));
+
+        let response = get(port, "localhost", "/hibernator-api/services/unknown/config", &[]).await;
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("content-type"), Some("text/plain"));
+        assert_eq!(response.text(), "Service 'unknown' not found");
+    }
+
+    #[tokio::test]
+    async fn start_durations_are_summarized() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let start = Utc::now() - Duration::hours(1);
+        for (i, seconds) in [3, 1, 2].into_iter().enumerate() {
+            let starting = start + Duration::seconds(i as i64 * 100);
+            DATABASE.put_state_at("durations", SiteState::Starting, starting).unwrap();
+            DATABASE.put_state_at("durations", SiteState::Up, starting + Duration::seconds(seconds)).unwrap();
+        }
+        let port = start_hibernator(env.load("", vec![env.site("durations", free_port(), "")])).await;
+
+        let response = get(port, "localhost", "/hibernator-api/services/durations/start-durations", &[]).await;
+        assert_eq!(response.status, 200);
+        let durations: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(durations["count"], 3);
+        assert_eq!(durations["min_ms"], 1000);
+        assert_eq!(durations["p50_ms"], 2000);
+        assert_eq!(durations["p95_ms"], 3000);
+        assert_eq!(durations["max_ms"], 3000);
+    }
+
+    #[tokio::test]
+    async fn api_wakes_are_recorded_as_such() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let port = start_hibernator(env.load("", vec![env.site("woken", free_port(), "")])).await;
+
+        let response = Response::parse(&send_raw(port, b"POST /hibernator-api/services/woken/wake HTTP/1.1\r\nHost: localhost\r\n\r\n").await);
+        assert_eq!(response.status, 200);
+
+        let trigger_source = tokio::time::timeout(TEST_TIMEOUT, async {
+            loop {
+                let response = get(port, "localhost", "/hibernator-api/state-history?service=woken", &[]).await;
+                let entries: Vec<serde_json::Value> = serde_json::from_slice(&response.body).unwrap();
+                if let Some(entry) = entries.iter().find(|entry| entry["state"] == "starting") {
+                    break entry["trigger_source"].clone();
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }).await.expect("the site never started");
+        assert_eq!(trigger_source, "api");
+    }
+
+    #[tokio::test]
+    async fn disabled_sites_cant_be_woken() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let (upstream_port, _) = upstream(ok_response("up")).await;
+        let port = start_hibernator(env.load("", vec![env.site("not-woken", upstream_port, "enabled = false")])).await;
+
+        let response = Response::parse(&send_raw(port, b"POST /hibernator-api/services/not-woken/wake HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\n\r\n").await);
+        assert_eq!(response.status, 409);
+        let error: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(error, serde_json::json!({"error": "Service 'not-woken' is disabled", "status": 409}));
+    }
 }
@@ -1,41 +1,79 @@
-use std::{fmt, ops::Deref};
+use std::{collections::{BTreeMap, HashSet}, fmt, io::Read, net::IpAddr, ops::Deref, path::{Path, PathBuf}, sync::{LazyLock, OnceLock, RwLock}, time::Duration};
+use anyhow::{anyhow, bail};
 use globset::{GlobBuilder, GlobMatcher};
-use serde::{de::{self, Visitor}, Deserialize, Deserializer, Serialize, Serializer};
+use regex::Regex;
+use ipnet::IpNet;
+use log::{error, warn};
+use serde::{de::{self, DeserializeOwned, Visitor}, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Parses durations like `300`, `90s`, `1h30m15s`, `1.5h` or `250ms` into milliseconds.
+/// Numbers without a unit are in `default_unit_ms` milliseconds.
+fn parse_duration_ms(value: &str, default_unit_ms: u64) -> Result<u64, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(String::from("duration cannot be empty"));
+    }
 
-fn deserialize_duration<'de, D>(deserializer: D) -> Result<u64, D::Error> where D: Deserializer<'de> {
-    struct DurationString;
+    let mut total_ms = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(number_end);
+        let unit_end = tail.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+
+        let number = number.parse::<f64>().map_err(|_| format!("invalid duration {value:?}"))?;
+        let multiplier = match unit.trim() {
+            "" if rest == value => default_unit_ms,
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" | "j" => 24 * 60 * 60 * 1000,
+            "" => return Err(format!("missing unit in duration {value:?}")),
+            unit => return Err(format!("unknown unit {unit:?} in duration {value:?}")),
+        };
+        total_ms += number * multiplier as f64;
+        rest = tail;
+    }
+
+    if !total_ms.is_finite() || total_ms >= u64::MAX as f64 {
+        return Err(format!("duration {value:?} is too large"));
+    }
+
+    Ok(total_ms.round() as u64)
+}
+
+/// Deserializes a duration expressed in `unit_ms` milliseconds, either as a number or as a string with units.
+/// Strings are rounded up to a whole unit, so that `"500ms"` in seconds doesn't become 0.
+/// When `allow_never` is set, `never` and `off` are accepted as [`KEEP_ALIVE_NEVER`].
+fn deserialize_duration_in<'de, D>(deserializer: D, unit_ms: u64, allow_never: bool) -> Result<u64, D::Error> where D: Deserializer<'de> {
+    struct DurationString {
+        unit_ms: u64,
+        allow_never: bool,
+    }
 
     impl Visitor<'_> for DurationString {
         type Value = u64;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("string")
-        }
-
-        fn visit_str<E>(self, mut value: &str) -> Result<u64, E> where E: de::Error {
-            let multiplier = match value.bytes().last() {
-                Some(b's') => {
-                    value = value.split_at(value.len() - 1).0;
-                    1
-                },
-                Some(b'm') => {
-                    value = value.split_at(value.len() - 1).0;
-                    60
-                },
-                Some(b'h') => {
-                    value = value.split_at(value.len() - 1).0;
-                    60 * 60
-                }
-                Some(b'd') | Some(b'j') => {
-                    value = value.split_at(value.len() - 1).0;
-                    60 * 60 * 24
-                }
-                _ => 1,
-            };
+            formatter.write_str("a number or a duration string such as \"1h30m\"")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<u64, E> where E: de::Error {
+            if self.allow_never && matches!(value.trim(), "never" | "off") {
+                return Ok(KEEP_ALIVE_NEVER);
+            }
+            let value_ms = parse_duration_ms(value, self.unit_ms).map_err(de::Error::custom)?;
 
-            let value = value.parse::<u64>().map_err(de::Error::custom)?;
+            Ok(value_ms.div_ceil(self.unit_ms))
+        }
 
-            Ok(value * multiplier)
+        fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E> where E: de::Error, {
+            if !v.is_finite() || v < 0.0 {
+                return Err(de::Error::custom(format!("invalid duration {v}")));
+            }
+            Ok(v.round() as u64)
         }
 
         fn visit_i8<E>(self, v: i8) -> Result<Self::Value, E> where E: de::Error, {
@@ -79,7 +117,75 @@ fn deserialize_duration<'de, D>(deserializer: D) -> Result<u64, D::Error> where
         }
     }
 
-    deserializer.deserialize_any(DurationString)
+    deserializer.deserialize_any(DurationString { unit_ms, allow_never })
+}
+
+
+/// Deserializes a duration in seconds.
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<u64, D::Error> where D: Deserializer<'de> {
+    deserialize_duration_in(deserializer, 1000, false)
+}
+
+/// Deserializes a keep_alive in seconds, where `never` or `off` disable hibernation.
+fn deserialize_keep_alive<'de, D>(deserializer: D) -> Result<u64, D::Error> where D: Deserializer<'de> {
+    deserialize_duration_in(deserializer, 1000, true)
+}
+
+/// Deserializes a duration in milliseconds.
+fn deserialize_duration_ms<'de, D>(deserializer: D) -> Result<u64, D::Error> where D: Deserializer<'de> {
+    deserialize_duration_in(deserializer, 1, false)
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error> where D: Deserializer<'de> {
+    struct SizeString;
+
+    impl Visitor<'_> for SizeString {
+        type Value = u64;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a size in bytes or a string with a unit such as \"512MiB\"")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<u64, E> where E: de::Error {
+            let value = value.trim();
+            let unit_start = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+            let (number, unit) = value.split_at(unit_start);
+            let multiplier: u64 = match unit.trim() {
+                "" | "B" => 1,
+                "KB" => 1000,
+                "MB" => 1000 * 1000,
+                "GB" => 1000 * 1000 * 1000,
+                "TB" => 1000 * 1000 * 1000 * 1000,
+                "K" | "KiB" => 1 << 10,
+                "M" | "MiB" => 1 << 20,
+                "G" | "GiB" => 1 << 30,
+                "T" | "TiB" => 1 << 40,
+                unit => return Err(de::Error::custom(format!("unknown size unit {unit:?}"))),
+            };
+
+            let number = number.parse::<u64>().map_err(de::Error::custom)?;
+
+            number.checked_mul(multiplier).ok_or_else(|| de::Error::custom("size is too large"))
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E> where E: de::Error, {
+            u64::try_from(v).map_err(de::Error::custom)
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E> where E: de::Error, {
+            Ok(v)
+        }
+    }
+
+    deserializer.deserialize_any(SizeString).map(Some)
+}
+
+fn deserialize_optional_duration<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error> where D: Deserializer<'de> {
+    deserialize_duration(deserializer).map(Some)
+}
+
+fn deserialize_optional_duration_ms<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error> where D: Deserializer<'de> {
+    deserialize_duration_ms(deserializer).map(Some)
 }
 
 /// The proxy is a feature to reduce friction when your service's APIs are used by other programs.
@@ -87,7 +193,7 @@ fn deserialize_duration<'de, D>(deserializer: D) -> Result<u64, D::Error> where
 /// If the server starts in time, the request will be processed out of the box, as if the server had been running.
 /// 
 /// Note: If you are relying on nginx to authenticate users, you might want to disable this feature to avoid users bypassing the authentication.
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum ProxyMode {
     /// Proxies all requests.
     #[serde(alias = "always")]
@@ -100,6 +206,12 @@ pub enum ProxyMode {
     #[serde(alias = "ready")]
     WhenReady,
 
+    /// Same as `WhenReady`, but while the upstream server is starting, one quick attempt is made before giving up.
+    /// This avoids serving the waiting page to users arriving right as the server becomes ready.
+    #[serde(alias = "when_starting")]
+    #[serde(alias = "when-starting")]
+    WhenStarting,
+
     /// Disables the proxy feature.
     #[serde(alias = "never")]
     Never,
@@ -115,39 +227,63 @@ impl ProxyMode {
     }
 }
 
-#[derive(Deserialize, Debug, Serialize)]
-pub struct ProxyTimeout(pub u64);
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct ProxyTimeout(#[serde(deserialize_with = "deserialize_duration_ms")] pub u64);
 impl Default for ProxyTimeout {
     fn default() -> Self {
         ProxyTimeout(28000)
     }
 }
 
-#[derive(Deserialize, Debug, Serialize)]
-pub struct ProxyCheckInterval(pub u64);
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct ProxyCheckInterval(#[serde(deserialize_with = "deserialize_duration_ms")] pub u64);
 impl Default for ProxyCheckInterval {
     fn default() -> Self {
         ProxyCheckInterval(500)
     }
 }
 
-#[derive(Deserialize, Debug, Serialize)]
-pub struct StartTimeout(pub u64);
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct UpstreamConnectTimeout(#[serde(deserialize_with = "deserialize_duration_ms")] pub u64);
+impl Default for UpstreamConnectTimeout {
+    fn default() -> Self {
+        UpstreamConnectTimeout(1000)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct HealthCheckTimeout(#[serde(deserialize_with = "deserialize_duration_ms")] pub u64);
+impl Default for HealthCheckTimeout {
+    fn default() -> Self {
+        HealthCheckTimeout(5000)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct StartTimeout(#[serde(deserialize_with = "deserialize_duration_ms")] pub u64);
 impl Default for StartTimeout {
     fn default() -> Self {
         StartTimeout(5*60*1000)
     }
 }
 
-#[derive(Deserialize, Debug, Serialize)]
-pub struct StartCheckInterval(pub u64);
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct StopTimeout(#[serde(deserialize_with = "deserialize_duration_ms")] pub u64);
+impl Default for StopTimeout {
+    fn default() -> Self {
+        StopTimeout(60*1000)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct StartCheckInterval(#[serde(deserialize_with = "deserialize_duration_ms")] pub u64);
 impl Default for StartCheckInterval {
     fn default() -> Self {
         StartCheckInterval(100)
     }
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct EtaSampleSize(pub usize);
 impl Default for EtaSampleSize {
     fn default() -> Self {
@@ -155,7 +291,7 @@ impl Default for EtaSampleSize {
     }
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct EtaPercentile(pub usize);
 impl Default for EtaPercentile {
     fn default() -> Self {
@@ -163,32 +299,126 @@ impl Default for EtaPercentile {
     }
 }
 
-pub struct GlobWrapper {
+pub const DEFAULT_HISTORY_MAX_HEADER_LINES: usize = 30;
+pub const DEFAULT_HISTORY_MAX_LINE_BYTES: usize = 2_000;
+pub const DEFAULT_HISTORY_VERBATIM_MAX_BYTES: usize = 64 * 1024;
+
+/// The keep_alive of sites that are never hibernated, set with `keep_alive = "never"` or `keep_alive = 0`
+pub const KEEP_ALIVE_NEVER: u64 = u64::MAX;
+
+/// How often sites that are never hibernated get their health checked
+const NEVER_HIBERNATED_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Paths browsers request on their own, which shouldn't keep sites awake.
+/// Applied to every site unless it sets `default_path_blacklist = false`.
+const DEFAULT_PATH_BLACKLIST: &[&str] = &[
+    "/favicon.ico",
+    "/favicon.svg",
+    "/favicon*.png",
+    "/apple-touch-icon*.png",
+    "/robots.txt",
+];
+
+static DEFAULT_PATH_BLACKLIST_MATCHERS: LazyLock<Vec<GlobMatcher>> = LazyLock::new(|| {
+    DEFAULT_PATH_BLACKLIST
+        .iter()
+        .map(|pattern| {
+            GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .expect("invalid default path blacklist pattern")
+                .compile_matcher()
+        })
+        .collect()
+});
+
+/// How the path lists of a site are matched
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PathMatchMode {
+    /// Glob patterns, where `*` doesn't match `/` (use `**` for that).
+    #[default]
+    #[serde(alias = "glob")]
+    Glob,
+
+    /// Regular expressions, matching anywhere in the path unless anchored with `^` and `$`.
+    #[serde(alias = "regex")]
+    Regex,
+}
+
+/// How the service is probed to tell whether it's up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum HealthCheckMode {
+    /// Sends `GET /` and waits for a response.
+    #[default]
+    #[serde(alias = "http")]
+    Http,
+
+    /// Only opens a connection, without sending anything. For services that don't speak HTTP.
+    #[serde(alias = "connect")]
+    #[serde(alias = "tcp")]
+    Connect,
+}
+
+#[derive(Clone)]
+enum PathMatcher {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+/// A pattern from a path list.
+/// It gets compiled according to the `path_match_mode` of its site once the config is parsed.
+#[derive(Clone)]
+pub struct PathPattern {
     pattern: String,
-    matcher: GlobMatcher,
+    matcher: Option<PathMatcher>,
+}
+
+impl PathPattern {
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    fn compile(&mut self, mode: PathMatchMode) -> anyhow::Result<()> {
+        let matcher = match mode {
+            PathMatchMode::Glob => PathMatcher::Glob(
+                GlobBuilder::new(&self.pattern)
+                    .case_insensitive(false)
+                    .literal_separator(true)
+                    .backslash_escape(true)
+                    .empty_alternates(true)
+                    .build()?
+                    .compile_matcher()
+            ),
+            PathMatchMode::Regex => PathMatcher::Regex(Regex::new(&self.pattern)?),
+        };
+        self.matcher = Some(matcher);
+        Ok(())
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        match &self.matcher {
+            Some(PathMatcher::Glob(matcher)) => matcher.is_match(path),
+            Some(PathMatcher::Regex(regex)) => regex.is_match(path),
+            None => {
+                error!("Path pattern {:?} was used before being compiled", self.pattern);
+                false
+            }
+        }
+    }
 }
 
-impl<'de> Deserialize<'de> for GlobWrapper {
+impl<'de> Deserialize<'de> for PathPattern {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
-        let s = String::deserialize(deserializer)?;
+        let pattern = String::deserialize(deserializer)?;
 
-        let glob = GlobBuilder::new(&s)
-            .case_insensitive(false)
-            .literal_separator(true)
-            .backslash_escape(true)
-            .empty_alternates(true)
-            .build()
-            .map_err(de::Error::custom)?
-            .compile_matcher();
-
-        Ok(GlobWrapper {
-            pattern: s,
-            matcher: glob,
+        Ok(PathPattern {
+            pattern,
+            matcher: None,
         })
     }
 }
 
-impl Serialize for GlobWrapper {
+impl Serialize for PathPattern {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -197,21 +427,121 @@ impl Serialize for GlobWrapper {
     }
 }
 
-impl fmt::Debug for GlobWrapper {
+impl fmt::Debug for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PathPattern(\"{}\")", self.pattern)
+    }
+}
+
+/// An IP range from an ip list.
+/// Accepts CIDR notation as well as plain addresses, which are treated as /32 or /128.
+#[derive(Clone)]
+pub struct IpRange {
+    entry: String,
+    net: IpNet,
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+
+        let net = match s.parse::<IpNet>() {
+            Ok(net) => net,
+            Err(_) => match s.parse::<IpAddr>() {
+                Ok(addr) => IpNet::from(addr),
+                Err(_) => return Err(de::Error::custom(format!("invalid IP address or CIDR range: {s:?}"))),
+            },
+        };
+
+        Ok(IpRange {
+            entry: s,
+            net,
+        })
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.entry)
+    }
+}
+
+impl fmt::Debug for IpRange {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "GlobWrapper(\"{}\")", self.pattern)
+        write!(f, "IpRange(\"{}\")", self.entry)
     }
 }
 
-impl Deref for GlobWrapper {
-    type Target = GlobMatcher;
+impl Deref for IpRange {
+    type Target = IpNet;
 
     fn deref(&self) -> &Self::Target {
-        &self.matcher
+        &self.net
+    }
+}
+
+/// Hibernation of sites when the system runs low on memory.
+/// When memory usage crosses the high-water mark, the awake site with the oldest activity is stopped,
+/// even if it's still within its keep_alive. Sites kept up by `min_uptime` are left alone.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MemoryPressureConfig {
+    /// Percentage of the system memory in use above which a site gets stopped, as reported by `/proc/meminfo`.
+    pub max_used_percent: u8,
+
+    /// How often memory usage is checked, in seconds or with a unit suffix.
+    /// At most one site is stopped per check, leaving time for the memory to be freed.
+    /// 
+    /// Defaults to 30 seconds.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub check_interval: Option<u64>,
+}
+
+impl MemoryPressureConfig {
+    pub fn check_interval(&self) -> u64 {
+        self.check_interval.unwrap_or(30)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A part of a site, served by its own service under some paths of the site.
+/// It is managed like a site of its own, named after the route, inheriting the settings of its site
+/// except for the paths, upstream, service and nginx configs.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RouteConfig {
+    /// The name of the route. Must be unique among sites and routes.
+    pub name: String,
+
+    /// The request paths handled by this route, matched according to the `path_match_mode` of the site.
+    pub paths: Vec<PathPattern>,
+
+    /// The port the service of this route listens to.
+    pub port: u16,
+
+    /// The name of the systemctl service that runs this route.
+    pub service_name: String,
+
+    /// Path to the nginx available config file of this route, typically a location snippet included by the site.
+    /// 
+    /// Defaults to `/etc/nginx/sites-available/{name}`.
+    #[serde(default)]
+    pub nginx_available_config: Option<String>,
+
+    /// Path to the nginx enabled config file of this route.
+    /// 
+    /// Defaults to `/etc/nginx/sites-enabled/{name}`.
+    #[serde(default)]
+    pub nginx_enabled_config: Option<String>,
+
+    /// Where the nginx hibernator config file of this route is located.
+    /// 
+    /// Defaults to `/etc/nginx/sites-available/nginx-hibernator`.
+    #[serde(default)]
+    pub nginx_hibernator_config: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SiteConfig {
     /// The name of the site. Must be unique.
     pub name: String,
@@ -247,12 +577,44 @@ pub struct SiteConfig {
     
     /// The port the service listens to.
     /// Used to determine if the service is up.
-    pub port: u16,
+    /// Exactly one of `port` and `upstream_socket` must be set.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// Path to the unix socket the service listens to, for services that don't listen on a TCP port.
+    /// Exactly one of `port` and `upstream_socket` must be set.
+    #[serde(default)]
+    pub upstream_socket: Option<String>,
+
+    /// The address the service listens to.
+    /// 
+    /// Defaults to `127.0.0.1`.
+    #[serde(default)]
+    pub upstream_host: Option<String>,
+
+    /// The Host header sent to the service when proxying requests.
+    /// Requests are still routed to the site using their original Host header.
+    /// 
+    /// Defaults to the Host header of the request.
+    #[serde(default)]
+    pub upstream_host_header: Option<String>,
+
+    /// Headers set on requests proxied to the service, replacing those the request already has.
+    /// In values, `$client` is replaced by the client IP and `$host` by the original Host header.
+    /// Headers whose value ends up empty are removed. Use `upstream_host_header` to change the Host header.
+    #[serde(default)]
+    pub proxy_set_headers: BTreeMap<String, String>,
 
     /// The path to the access log file.
     /// Your nginx configuration must log the requests to this file.
     pub access_log: String,
 
+    /// Suffix of the most recent rotated access log, read along with the access log right after a rotation.
+    /// A compressed version with an additional `.gz` suffix is also accepted.
+    /// Defaults to `.1`.
+    #[serde(default)]
+    pub log_rotation_suffix: Option<String>,
+
     /// Optional filter to match lines in the access log.
     /// Only lines containing this string will be considered.
     #[serde(default)]
@@ -262,6 +624,28 @@ pub struct SiteConfig {
     /// Commands `systemctl start` and `systemctl stop` will be run with this name.
     pub service_name: String,
 
+    /// URL to POST a JSON notification to whenever the site changes state.
+    #[serde(default)]
+    pub state_change_webhook: Option<String>,
+
+    /// The command used in place of `systemctl`, such as `systemctl --user` or `sudo systemctl`.
+    /// It is split on whitespace and run without a shell.
+    /// Defaults to `systemctl`.
+    #[serde(default)]
+    pub systemctl_prefix: Option<String>,
+
+    /// The command reloading nginx after the enabled config of this site is switched.
+    /// 
+    /// Defaults to the global `nginx_reload_command`.
+    #[serde(default)]
+    pub nginx_reload_command: Option<String>,
+
+    /// The command checking the nginx config before and after the enabled config of this site is switched.
+    /// 
+    /// Defaults to the global `nginx_test_command`.
+    #[serde(default)]
+    pub nginx_test_command: Option<String>,
+
     /// The hostnames that the service listens to.
     /// It's used so that the hibernator knows which site to start upon receiving a request.
     pub hosts: Vec<String>,
@@ -278,41 +662,119 @@ pub struct SiteConfig {
     #[serde(default)]
     pub proxy_timeout_ms: ProxyTimeout,
 
+    /// Maximum time to wait before giving up on the proxy for requests issued by browsers, in milliseconds.
+    /// Browsers get an auto-refreshing landing page, so they can afford a shorter wait.
+    /// Defaults to `proxy_timeout_ms`.
+    #[serde(default, deserialize_with = "deserialize_optional_duration_ms")]
+    pub browser_proxy_timeout_ms: Option<u64>,
+
     /// Interval time to check if the proxy is up, in milliseconds.
     #[serde(default)]
     pub proxy_check_interval_ms: ProxyCheckInterval,
 
-    /// List of glob patterns to match the paths that should NOT count as activity.
+    /// Maximum size of request bodies held until the site is up, in bytes.
+    /// When set, requests carrying a body that would get the landing page (see `proxy_mode`) are instead
+    /// kept open until the site is up and then proxied, so that form submissions are not lost.
+    /// Larger bodies get the landing page as usual. Has no effect when the proxy mode is `Never`.
+    #[serde(default)]
+    pub replay_body_max_bytes: Option<usize>,
+
+    /// Maximum time to wait for a connection to the upstream, in milliseconds.
+    /// Applies to proxied requests and health checks. A connection that times out counts as not ready.
+    #[serde(default)]
+    pub upstream_connect_timeout_ms: UpstreamConnectTimeout,
+
+    /// Maximum number of concurrent health probes made by requests waiting for the service to wake up.
+    /// Requests are proxied once their probe succeeds, which avoids hammering a booting service. Unlimited by default.
+    #[serde(default)]
+    pub wake_concurrency: Option<usize>,
+
+    /// List of patterns to match the paths that should NOT count as activity.
     /// Requests to these paths will NOT reset the keep-alive timer and will NOT wake up the service.
     #[serde(default)]
     #[serde(alias = "blacklisted_paths")]
     #[serde(alias = "blacklist_paths")]
     #[serde(alias = "path_denylist")]
-    pub path_blacklist: Option<Vec<GlobWrapper>>,
+    pub path_blacklist: Option<Vec<PathPattern>>,
+
+    /// Whether requests for favicons and `robots.txt` should be ignored like blacklisted paths.
+    /// Browsers request them on their own, which would otherwise keep the site awake.
+    /// 
+    /// Defaults to `true`.
+    #[serde(default)]
+    pub default_path_blacklist: Option<bool>,
+
+    /// Whether requests to blacklisted paths should start the service anyway.
+    /// They still don't reset the keep-alive timer and are still answered with a 503 until the service is up.
+    /// 
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub wake_on_blacklisted_paths: Option<bool>,
+
+    /// Whether the hibernator manages this site.
+    /// A disabled site is never stopped, and requests reaching the hibernator are passed through to it.
+    /// If it was hibernated, it is started once so that it doesn't stay down, and nginx is pointed back at it when it is up.
+    /// 
+    /// Defaults to `true`.
+    #[serde(default)]
+    pub enabled: Option<bool>,
+
+    /// List of patterns to match the only paths that can wake up the service.
+    /// Requests to other paths are answered without waking the service.
+    /// The path blacklist takes precedence over this list.
+    #[serde(default)]
+    #[serde(alias = "wake_path_allowlist")]
+    pub wake_path_whitelist: Option<Vec<PathPattern>>,
+
+    /// How the patterns of `path_blacklist` and `wake_path_whitelist` are matched. See [`PathMatchMode`].
+    /// 
+    /// Defaults to `Glob`.
+    #[serde(default)]
+    pub path_match_mode: PathMatchMode,
 
-    /// List of IP prefixes that should NOT count as activity.
+    /// Paths of the site served by other services, each hibernated independently. See [`RouteConfig`].
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+
+    /// The paths handled by this site, when it was created from a route.
+    #[serde(skip_deserializing)]
+    pub route_paths: Option<Vec<PathPattern>>,
+
+    /// List of IP addresses or CIDR ranges that should NOT count as activity.
     /// Requests from these IPs will NOT reset the keep-alive timer and will NOT wake up the service.
     #[serde(default)]
     #[serde(alias = "blacklisted_ips")]
     #[serde(alias = "blacklist_ips")]
     #[serde(alias = "ip_denylist")]
-    pub ip_blacklist: Option<Vec<String>>,
+    pub ip_blacklist: Option<Vec<IpRange>>,
 
-    /// List of IP prefixes that are allowed to wake up the service.
+    /// List of IP addresses or CIDR ranges that are allowed to wake up the service.
     /// All other IPs will not count as activity.
     #[serde(default)]
     #[serde(alias = "whitelisted_ips")]
     #[serde(alias = "whitelist_ips")]
     #[serde(alias = "ip_allowlist")]
-    pub ip_whitelist: Option<Vec<String>>,
+    pub ip_whitelist: Option<Vec<IpRange>>,
 
     // TODO: user-agent filters
 
     /// The time in seconds to keep the service running after the last request.
     /// The service will be stopped after this time.
-    #[serde(deserialize_with = "deserialize_duration")]
+    /// `never` (or `off`, or 0) keeps the service running once started, while still proxying and recording metrics for it.
+    #[serde(deserialize_with = "deserialize_keep_alive")]
     pub keep_alive: u64,
 
+    /// A shell command printing the number of connections the service is currently serving, like `ss -Htn state established sport = :8080 | wc -l`.
+    /// When set, the site isn't hibernated while it prints more than 0, as nginx only logs downloads and streams once they complete.
+    /// Memory pressure still stops the site.
+    #[serde(default)]
+    pub active_connections_command: Option<String>,
+
+    /// The minimum time the service stays up after starting, in seconds or with a unit suffix, regardless of the access log.
+    /// Prevents a site from being stopped right after starting when its last request predates the start.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub min_uptime: Option<u64>,
+
     /// The time to wait before giving up on waiting for the service to start, in milliseconds.
     #[serde(default)]
     pub start_timeout_ms: StartTimeout,
@@ -321,10 +783,85 @@ pub struct SiteConfig {
     #[serde(default)]
     pub start_check_interval_ms: StartCheckInterval,
 
+    /// A shell command telling whether the service is up, by exiting with status 0.
+    /// Replaces probing the upstream. It runs as the hibernator's user, frequently while the service starts, so it should be fast.
+    /// It is killed after `health_check_timeout_ms`.
+    #[serde(default)]
+    pub health_check_command: Option<String>,
+
+    /// Maximum time a health check may take, in milliseconds. A health check that times out counts as not ready.
+    /// 
+    /// Defaults to `5000`.
+    #[serde(default)]
+    pub health_check_timeout_ms: HealthCheckTimeout,
+
+    /// How the upstream and the readiness ports are probed. See [`HealthCheckMode`].
+    /// 
+    /// Defaults to `Http`.
+    #[serde(default)]
+    pub health_check_mode: HealthCheckMode,
+
+    /// A string the response of the service must contain for it to be considered up.
+    /// Useful to tell the service apart from something else listening on its port.
+    /// It must show up within `health_check_timeout_ms`, so a stalled response counts as not ready.
+    #[serde(default)]
+    pub health_check_expect_body_contains: Option<String>,
+
+    /// Additional ports probed concurrently with the main upstream while the service starts.
+    #[serde(default)]
+    pub readiness_ports: Vec<u16>,
+
+    /// Number of probes, the main upstream included, that must respond for the service to be considered started.
+    /// Defaults to all of them.
+    #[serde(default)]
+    pub readiness_quorum: Option<usize>,
+
+    /// Number of consecutive successful readiness checks required for a starting service to be considered up.
+    /// Also the number of consecutive failed checks required for a stopping service to be considered down.
+    /// Guards against the lingering socket of a previous instance answering once.
+    /// 
+    /// Defaults to 1.
+    #[serde(default)]
+    pub health_check_success_threshold: Option<u32>,
+
+    /// The time to wait for the service to stop responding after stopping it, in milliseconds.
+    /// The site is reported as stopping meanwhile, and checked at `start_check_interval_ms`.
+    #[serde(default)]
+    pub stop_timeout_ms: StopTimeout,
+
     /// Path to the landing page folder for this specific site.
     /// If not set, uses the global landing_folder.
     #[serde(default)]
     pub landing_folder: Option<String>,
+
+    /// How much memory the site uses when it's up, in megabytes.
+    /// Only used to estimate the memory saved by hibernation in the fleet status.
+    #[serde(default)]
+    pub memory_estimate_mb: Option<u64>,
+
+    /// Maximum number of request header lines stored in the request history.
+    /// 
+    /// Defaults to the global `history_max_header_lines`.
+    #[serde(default)]
+    pub history_max_header_lines: Option<usize>,
+
+    /// Maximum size of each request header line stored in the request history, in bytes.
+    /// 
+    /// Defaults to the global `history_max_line_bytes`.
+    #[serde(default)]
+    pub history_max_line_bytes: Option<usize>,
+
+    /// Store request headers verbatim in the request history, including the request line and the X-Real-IP header.
+    /// Line limits don't apply, only `history_verbatim_max_bytes` does.
+    /// Useful when investigating issues with a specific site.
+    #[serde(default)]
+    pub history_verbatim_headers: bool,
+
+    /// Maximum total size of the headers stored when `history_verbatim_headers` is enabled, in bytes.
+    /// 
+    /// Defaults to 65536.
+    #[serde(default)]
+    pub history_verbatim_max_bytes: Option<usize>,
 }
 
 impl SiteConfig {
@@ -349,64 +886,421 @@ impl SiteConfig {
         }
     }
 
-    pub fn landing_folder<'a>(&'a self, config: &'a Config) -> &'a str {
-        match &self.landing_folder {
-            Some(folder) => folder,
-            None => config.top_level.landing_folder(),
+    pub fn log_rotation_suffix(&self) -> &str {
+        match &self.log_rotation_suffix {
+            Some(suffix) => suffix,
+            None => ".1",
         }
     }
-}
 
-#[derive(Debug, Deserialize)]
-pub struct TopLevelConfig {
-    /// The port the hibernator listens to.
-    /// This port should never be exposed to the internet.
-    /// 
-    /// Defaults to `7878`.
-    #[serde(default)]
-    pub hibernator_port: Option<u16>,
+    pub fn readiness_quorum(&self) -> usize {
+        self.readiness_quorum.unwrap_or(self.readiness_ports.len() + 1)
+    }
 
-    /// Where to store the database
-    /// 
-    /// Defaults to `./data.mdb`
-    #[serde(default)]
-    pub database_path: Option<String>,
+    pub fn proxy_timeout_ms(&self, is_browser: bool) -> u64 {
+        match (is_browser, self.browser_proxy_timeout_ms) {
+            (true, Some(timeout)) => timeout,
+            _ => self.proxy_timeout_ms.0,
+        }
+    }
 
-    /// Path to the landing page folder containing index.html and assets.
-    /// 
-    /// Defaults to `/usr/share/nginx/html/nginx-hibernator-landing`
-    #[serde(default)]
-    pub landing_folder: Option<String>,
+    pub fn systemctl_prefix(&self) -> &str {
+        match &self.systemctl_prefix {
+            Some(prefix) => prefix,
+            None => "systemctl",
+        }
+    }
 
-    /// SHA-256 hash of the API key required for accessing the hibernator API.
-    /// If not set, API authentication is disabled.
-    /// 
-    /// Generate with: `echo -n "your-api-key" | sha256sum`
-    #[serde(default)]
-    pub api_key_sha256: Option<String>,
-}
+    /// The arguments running a systemctl action on the service.
+    /// They are passed directly to the program, so that service names can't be interpreted by a shell.
+    pub fn systemctl_argv<'a>(&'a self, action: &'a str) -> Vec<&'a str> {
+        let mut argv: Vec<&str> = self.systemctl_prefix().split_whitespace().collect();
+        argv.push(action);
+        argv.push(&self.service_name);
+        argv
+    }
 
-impl TopLevelConfig {
-    pub fn hibernator_port(&self) -> u16 {
-        match &self.hibernator_port {
-            Some(port) => *port,
-            None => 7878,
-        }
+    /// The arguments reloading nginx, split on whitespace and run without a shell like `systemctl_prefix`.
+    pub fn nginx_reload_argv<'a>(&'a self, config: &'a Config) -> Vec<&'a str> {
+        self.nginx_reload_command.as_deref().unwrap_or(config.top_level.nginx_reload_command()).split_whitespace().collect()
     }
 
-    pub fn database_path(&self) -> &str {
-        match &self.database_path {
-            Some(p) => p,
-            None => "./data.mdb"
+    /// The arguments checking the nginx config, split on whitespace and run without a shell like `systemctl_prefix`.
+    pub fn nginx_test_argv<'a>(&'a self, config: &'a Config) -> Vec<&'a str> {
+        self.nginx_test_command.as_deref().unwrap_or(config.top_level.nginx_test_command()).split_whitespace().collect()
+    }
+
+    pub fn upstream_host(&self) -> &str {
+        match &self.upstream_host {
+            Some(host) => host,
+            None => "127.0.0.1",
         }
     }
 
+    pub fn history_max_header_lines(&self, config: &Config) -> usize {
+        match self.history_max_header_lines {
+            Some(lines) => lines,
+            None => config.top_level.history_max_header_lines(),
+        }
+    }
+
+    pub fn history_max_line_bytes(&self, config: &Config) -> usize {
+        match self.history_max_line_bytes {
+            Some(bytes) => bytes,
+            None => config.top_level.history_max_line_bytes(),
+        }
+    }
+
+    pub fn history_verbatim_max_bytes(&self) -> usize {
+        self.history_verbatim_max_bytes.unwrap_or(DEFAULT_HISTORY_VERBATIM_MAX_BYTES)
+    }
+
+    pub fn default_path_blacklist(&self) -> bool {
+        self.default_path_blacklist.unwrap_or(true)
+    }
+
+    pub fn health_check_success_threshold(&self) -> u32 {
+        self.health_check_success_threshold.unwrap_or(1)
+    }
+
+    pub fn wake_on_blacklisted_paths(&self) -> bool {
+        self.wake_on_blacklisted_paths.unwrap_or(false)
+    }
+
+    /// Whether the site gets stopped when idle, which `keep_alive = "never"` disables
+    pub fn hibernates(&self) -> bool {
+        self.keep_alive != KEEP_ALIVE_NEVER
+    }
+
+    /// How long to wait before checking the site again, when nothing tells when it could be stopped
+    pub fn recheck_interval(&self) -> Duration {
+        match self.hibernates() {
+            true => Duration::from_secs(self.keep_alive),
+            false => NEVER_HIBERNATED_CHECK_INTERVAL,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// Whether requests to this path are handled by this site rather than by one of its routes, or by another route of its parent site.
+    pub fn handles_path(&self, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or(path);
+        match &self.route_paths {
+            Some(route_paths) => route_paths.iter().any(|pattern| pattern.is_match(path)),
+            None => !self.routes.iter().any(|route| route.paths.iter().any(|pattern| pattern.is_match(path))),
+        }
+    }
+
+    /// Whether requests to this path should NOT count as activity, according to `path_blacklist` and the default blacklist.
+    pub fn is_path_blacklisted(&self, path: &str) -> bool {
+        if self.path_blacklist.iter().flatten().any(|blacklist_path| blacklist_path.is_match(path)) {
+            return true;
+        }
+
+        self.default_path_blacklist() && DEFAULT_PATH_BLACKLIST_MATCHERS.iter().any(|matcher| matcher.is_match(path))
+    }
+
+    /// Whether requests to this path can wake the service up, according to `wake_path_whitelist`.
+    pub fn is_wake_path(&self, path: &str) -> bool {
+        match &self.wake_path_whitelist {
+            Some(wake_paths) => wake_paths.iter().any(|wake_path| wake_path.is_match(path)),
+            None => true,
+        }
+    }
+
+    pub fn landing_folder<'a>(&'a self, config: &'a Config) -> &'a str {
+        match &self.landing_folder {
+            Some(folder) => folder,
+            None => config.top_level.landing_folder(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopLevelConfig {
+    /// The port the hibernator listens to.
+    /// This port should never be exposed to the internet.
+    /// 
+    /// Defaults to `7878`.
+    #[serde(default)]
+    pub hibernator_port: Option<u16>,
+
+    /// The address the hibernator listens on.
+    /// Only loopback and private addresses are accepted, unless `i_know_this_is_dangerous` is set.
+    /// 
+    /// Defaults to `127.0.0.1`.
+    #[serde(default)]
+    pub bind_address: Option<String>,
+
+    /// Allows `bind_address` to be a public address.
+    /// The hibernator API and the proxy would then be reachable without going through nginx.
+    #[serde(default)]
+    pub i_know_this_is_dangerous: bool,
+
+    /// Where to store the database
+    /// 
+    /// Defaults to `./data.mdb`
+    #[serde(default)]
+    pub database_path: Option<String>,
+
+    /// Maximum size of the database, in bytes or with a unit suffix (`512MiB`, `2GiB`).
+    /// The hibernator stops recording requests once it is reached.
+    /// 
+    /// Defaults to `1GiB`
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub database_map_size: Option<u64>,
+
+    /// Maximum size of request bodies, in bytes or with a unit suffix (`32MiB`).
+    /// Bodies are held in memory, so larger ones are refused with a 413 before being read.
+    /// 
+    /// Defaults to `32MiB`
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_request_body_bytes: Option<u64>,
+
+    /// Path to the landing page folder containing index.html and assets.
+    /// 
+    /// Defaults to `/usr/share/nginx/html/nginx-hibernator-landing`
+    #[serde(default)]
+    pub landing_folder: Option<String>,
+
+    /// SHA-256 hash of the API key required for accessing the hibernator API, sent in the `X-Api-Key` header.
+    /// If neither this nor `api_token` is set, API authentication is disabled.
+    /// 
+    /// Generate with: `echo -n "your-api-key" | sha256sum`
+    #[serde(default)]
+    pub api_key_sha256: Option<String>,
+
+    /// Token accepted in an `Authorization: Bearer` header for accessing the hibernator API.
+    /// When both this and `api_key_sha256` are set, either credential is accepted.
+    #[serde(default)]
+    pub api_token: Option<String>,
+
+    /// How long clients may cache the responses of the state history and metrics endpoints, in seconds or with a unit suffix.
+    /// Other API responses are never cached.
+    /// 
+    /// Defaults to 0 (no caching).
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub api_cache_max_age: Option<u64>,
+
+    /// The command reloading nginx once a site's enabled config was switched. Sites can override it.
+    /// It is split on whitespace and run directly, not through a shell.
+    /// 
+    /// Defaults to `nginx -s reload`.
+    #[serde(default)]
+    pub nginx_reload_command: Option<String>,
+
+    /// The command checking the nginx config around switches. Sites can override it.
+    /// It is split on whitespace and run directly, not through a shell.
+    /// 
+    /// Defaults to `nginx -t`.
+    #[serde(default)]
+    pub nginx_test_command: Option<String>,
+
+    /// The path prefix under which the hibernator API is served.
+    /// Requests to other paths are proxied to the sites.
+    /// 
+    /// Defaults to `/hibernator-api`.
+    #[serde(default)]
+    pub api_prefix: Option<String>,
+
+    /// Whether the hibernator API is served at all.
+    /// When disabled, requests under `api_prefix` are handled like any other request.
+    /// 
+    /// Defaults to `true`.
+    #[serde(default)]
+    pub api_enabled: Option<bool>,
+
+    /// The minimum keep_alive of sites, in seconds or with a unit suffix.
+    /// Sites with a lower keep_alive use this value instead, which prevents them from being stopped and started repeatedly.
+    /// A keep_alive of 0 isn't raised, as it means never stopping the site.
+    /// 
+    /// Defaults to `30s`
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub min_keep_alive: Option<u64>,
+
+    /// How long to keep the request history, in seconds or with a unit suffix (`30d`).
+    /// Older entries are deleted periodically.
+    /// 
+    /// Defaults to keeping everything.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub history_retention: Option<u64>,
+
+    /// How long to keep the state history, in seconds or with a unit suffix (`90d`).
+    /// Older entries are deleted periodically, except the last known state of each site.
+    /// Note that the state history is used to compute ETAs and metrics.
+    /// 
+    /// Defaults to keeping everything.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub state_history_retention: Option<u64>,
+
+    /// The request header holding the client IP, as set by the reverse proxy in front of the hibernator.
+    /// When set, only this header is trusted. For `X-Forwarded-For`, the first entry is used.
+    /// 
+    /// Defaults to `X-Real-IP`, falling back to the first entry of `X-Forwarded-For`.
+    #[serde(default)]
+    pub trusted_proxy_ip_header: Option<String>,
+
+    /// A directory of additional config files, relative to this one.
+    /// Each `.toml` file in it can define `[[sites]]`, which are added to the ones of this file.
+    #[serde(default)]
+    pub include_dir: Option<String>,
+
+    /// Maximum number of request header lines stored in the request history.
+    /// Sites can override it.
+    /// 
+    /// Defaults to 30.
+    #[serde(default)]
+    pub history_max_header_lines: Option<usize>,
+
+    /// Maximum size of each request header line stored in the request history, in bytes.
+    /// Sites can override it.
+    /// 
+    /// Defaults to 2000.
+    #[serde(default)]
+    pub history_max_line_bytes: Option<usize>,
+
+    /// Maximum random offset applied to each periodic site check, in milliseconds.
+    /// Spreads the checks of sites sharing the same keep_alive so that access logs aren't all read at once.
+    /// 
+    /// Defaults to 0 (disabled).
+    #[serde(default)]
+    pub check_jitter_ms: Option<u64>,
+
+    /// Stopping sites when the system runs low on memory. See [`MemoryPressureConfig`].
+    /// 
+    /// Disabled by default.
+    #[serde(default)]
+    pub memory_pressure: Option<MemoryPressureConfig>,
+
+    /// File to which the hibernator writes its own access log, with a line per request it handles.
+    /// API requests aren't logged.
+    #[serde(default)]
+    pub access_log_output: Option<String>,
+
+    /// Format of the lines of `access_log_output`, where `$variable` or `${variable}` is replaced by the value of the request.
+    /// Available variables: time, client, method, url, host, service, result, status, cold, browser, duration_ms, wake_wait_ms, upstream_ms.
+    /// 
+    /// Defaults to [`request_log::DEFAULT_FORMAT`](crate::request_log::DEFAULT_FORMAT).
+    #[serde(default)]
+    pub access_log_format: Option<String>,
+
+    /// Status code answered to requests without a Host header or for a host matching no site.
+    /// 
+    /// Defaults to 404.
+    #[serde(default)]
+    pub unknown_site_status: Option<u16>,
+
+    /// Body answered to requests without a Host header or for a host matching no site.
+    /// 
+    /// Defaults to `Not Found`.
+    #[serde(default)]
+    pub unknown_site_body: Option<String>,
+
+    /// File whose content is answered to requests without a Host header or for a host matching no site.
+    /// Served as HTML if its name ends with `.html`, as plain text otherwise.
+    /// Takes the place of `unknown_site_body`.
+    #[serde(default)]
+    pub unknown_site_body_file: Option<String>,
+
+    /// MaxMind database (`.mmdb`, Country or City) used to record the country of clients in the request history.
+    /// Loaded once, and again on reload only if the path changes.
+    /// 
+    /// Disabled by default.
+    #[serde(default)]
+    pub geoip_database: Option<String>,
+}
+
+impl TopLevelConfig {
+    pub fn hibernator_port(&self) -> u16 {
+        match &self.hibernator_port {
+            Some(port) => *port,
+            None => 7878,
+        }
+    }
+
+    pub fn bind_address(&self) -> &str {
+        match &self.bind_address {
+            Some(address) => address,
+            None => "127.0.0.1",
+        }
+    }
+
+    pub fn database_path(&self) -> &str {
+        match &self.database_path {
+            Some(p) => p,
+            None => "./data.mdb"
+        }
+    }
+
+    pub fn min_keep_alive(&self) -> u64 {
+        self.min_keep_alive.unwrap_or(30)
+    }
+
+    pub fn max_request_body_bytes(&self) -> u64 {
+        self.max_request_body_bytes.unwrap_or(32 << 20)
+    }
+
+    pub fn database_map_size(&self) -> usize {
+        let size = self.database_map_size.unwrap_or(1 << 30);
+        let size = usize::try_from(size).unwrap_or(usize::MAX);
+
+        // LMDB requires the map size to be a multiple of the page size
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        let page_size = usize::try_from(page_size).ok().filter(|p| *p > 0).unwrap_or(4096);
+        size.div_ceil(page_size).saturating_mul(page_size)
+    }
+
     pub fn landing_folder(&self) -> &str {
         match &self.landing_folder {
             Some(p) => p,
             None => "/usr/share/nginx/html/nginx-hibernator-landing"
         }
     }
+
+    pub fn api_prefix(&self) -> &str {
+        match &self.api_prefix {
+            Some(prefix) => prefix,
+            None => "/hibernator-api",
+        }
+    }
+
+    pub fn api_enabled(&self) -> bool {
+        self.api_enabled.unwrap_or(true)
+    }
+
+    pub fn history_max_header_lines(&self) -> usize {
+        self.history_max_header_lines.unwrap_or(DEFAULT_HISTORY_MAX_HEADER_LINES)
+    }
+
+    pub fn history_max_line_bytes(&self) -> usize {
+        self.history_max_line_bytes.unwrap_or(DEFAULT_HISTORY_MAX_LINE_BYTES)
+    }
+
+    pub fn nginx_reload_command(&self) -> &str {
+        self.nginx_reload_command.as_deref().unwrap_or("nginx -s reload")
+    }
+
+    pub fn nginx_test_command(&self) -> &str {
+        self.nginx_test_command.as_deref().unwrap_or("nginx -t")
+    }
+
+    pub fn check_jitter_ms(&self) -> u64 {
+        self.check_jitter_ms.unwrap_or(0)
+    }
+
+    pub fn access_log_format(&self) -> &str {
+        self.access_log_format.as_deref().unwrap_or(crate::request_log::DEFAULT_FORMAT)
+    }
+
+    pub fn unknown_site_status(&self) -> u16 {
+        self.unknown_site_status.unwrap_or(404)
+    }
+
+    pub fn unknown_site_body(&self) -> &str {
+        self.unknown_site_body.as_deref().unwrap_or("Not Found")
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -417,3 +1311,688 @@ pub struct Config {
     #[serde(default)]
     pub sites: Vec<SiteConfig>,
 }
+
+static CURRENT_CONFIG: RwLock<Option<&'static Config>> = RwLock::new(None);
+
+/// Returns the config currently in use.
+/// It can change over time as the config gets reloaded.
+pub fn current_config() -> &'static Config {
+    CURRENT_CONFIG.read().expect("config lock poisoned").expect("config not loaded yet")
+}
+
+pub fn set_current_config(config: &'static Config) {
+    *CURRENT_CONFIG.write().expect("config lock poisoned") = Some(config);
+}
+
+/// Environment variable that can hold the whole config, used when no config path is given on the command line
+const CONFIG_VAR: &str = "HIBERNATOR_CONFIG";
+
+/// Config read from stdin, kept for reloads and the database since stdin can only be read once
+static STDIN_CONFIG: OnceLock<String> = OnceLock::new();
+
+/// Where the config is read from.
+pub enum ConfigSource {
+    File(String),
+    /// Used when the config path is `-`
+    Stdin,
+    /// Used when no config path is given and `HIBERNATOR_CONFIG` is set
+    Env,
+}
+
+impl ConfigSource {
+    /// Reads the config. Only files have their permissions checked, as the others can't be tampered with by other users.
+    pub fn read(&self) -> anyhow::Result<String> {
+        match self {
+            ConfigSource::File(path) => {
+                check_config_file_permissions(Path::new(path))?;
+                std::fs::read_to_string(path).map_err(|e| anyhow!("could not read config file: {e}"))
+            }
+            ConfigSource::Stdin => {
+                if let Some(data) = STDIN_CONFIG.get() {
+                    return Ok(data.clone());
+                }
+                let mut data = String::new();
+                std::io::stdin().read_to_string(&mut data).map_err(|e| anyhow!("could not read config from stdin: {e}"))?;
+                Ok(STDIN_CONFIG.get_or_init(|| data).clone())
+            }
+            ConfigSource::Env => std::env::var(CONFIG_VAR).map_err(|e| anyhow!("could not read config from {CONFIG_VAR}: {e}")),
+        }
+    }
+
+    /// Path of the main config file, used in errors and to resolve `include_dir`.
+    /// Config that doesn't come from a file resolves it from the working directory.
+    fn path(&self) -> &Path {
+        match self {
+            ConfigSource::File(path) => Path::new(path),
+            ConfigSource::Stdin => Path::new("<stdin>"),
+            ConfigSource::Env => Path::new(CONFIG_VAR),
+        }
+    }
+}
+
+/// Config file of the running test, as tests have no command line to take it from
+#[cfg(test)]
+pub static TEST_CONFIG_PATH: RwLock<Option<String>> = RwLock::new(None);
+
+/// Where the config comes from, according to the command line.
+/// The config path is the first argument that isn't a flag (so `--config path` works too), `-` meaning stdin.
+pub fn config_source() -> ConfigSource {
+    #[cfg(test)]
+    if let Some(path) = TEST_CONFIG_PATH.read().expect("test config lock poisoned").clone() {
+        return ConfigSource::File(path);
+    }
+
+    let mut args = std::env::args().skip(1).peekable();
+    args.next_if(|arg| arg == "status");
+    match args.find(|arg| !arg.starts_with("--")) {
+        Some(path) if path == "-" => ConfigSource::Stdin,
+        Some(path) => ConfigSource::File(path),
+        None if std::env::var_os(CONFIG_VAR).is_some() => ConfigSource::Env,
+        None => ConfigSource::File(String::from("config.toml")),
+    }
+}
+
+/// Whether the hibernator was asked to check its config and exit, with `--check`.
+pub fn check_mode() -> bool {
+    std::env::args().skip(1).any(|arg| arg == "--check")
+}
+
+/// Makes sure a config file can't be tampered with by other users
+fn check_config_file_permissions(config_path: &Path) -> anyhow::Result<()> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        // Checks apply to the file the config path resolves to, not to a symlink pointing at it
+        let link_metadata = std::fs::symlink_metadata(config_path).map_err(|e| anyhow!("could not stat config file {}: {e}", config_path.display()))?;
+        let resolved_path = if link_metadata.file_type().is_symlink() {
+            std::fs::canonicalize(config_path).map_err(|e| anyhow!("could not resolve config file symlink {}: {e}", config_path.display()))?
+        } else {
+            PathBuf::from(config_path)
+        };
+        let metadata = std::fs::metadata(&resolved_path).map_err(|e| anyhow!(
+            "could not stat config file {}: {e} (network filesystems may refuse this)", resolved_path.display()
+        ))?;
+        let uid = metadata.uid();
+        let mode = metadata.mode();
+        let current_uid = unsafe { libc::getuid() };
+
+        // The kernel's overflow uid, reported for owners a network filesystem cannot map
+        const OVERFLOW_UID: u32 = 65534;
+        if uid == OVERFLOW_UID && current_uid != OVERFLOW_UID {
+            bail!(
+                "Config file {} has an unmapped owner (uid {uid}), which usually means it is on a network filesystem. Move it to a local filesystem",
+                resolved_path.display()
+            );
+        }
+
+        if uid != current_uid {
+            bail!("Config file {} should be owned by current user (uid {current_uid}), but is owned by uid {uid}", resolved_path.display());
+        }
+
+        if mode & 0o002 != 0 {
+            bail!("Config file {} should not be writable by other users", resolved_path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// A file of the `include_dir` directory, which can only define sites
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncludedConfig {
+    #[serde(default)]
+    sites: Vec<SiteConfig>,
+}
+
+/// Fields every site must end up with, either set by the site itself or by `[defaults]`
+const REQUIRED_SITE_FIELDS: &[&str] = &["name", "access_log", "service_name", "hosts", "keep_alive"];
+
+/// Fills the sites of a parsed config file with the `[defaults]` values they don't set themselves
+fn apply_site_defaults(table: &mut toml::Table, defaults: &toml::Table) -> anyhow::Result<()> {
+    let Some(toml::Value::Array(sites)) = table.get_mut("sites") else {
+        return Ok(());
+    };
+
+    for (i, site) in sites.iter_mut().enumerate() {
+        let toml::Value::Table(site) = site else {
+            continue;
+        };
+        for (key, value) in defaults {
+            site.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+
+        let name = site.get("name").and_then(|name| name.as_str()).map(String::from).unwrap_or_else(|| format!("#{}", i + 1));
+        for field in REQUIRED_SITE_FIELDS {
+            if !site.contains_key(*field) {
+                bail!("Site {name} is missing {field}, which isn't set in [defaults] either");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a config file, applying `[defaults]` to its sites if there are any
+pub fn parse_config_file<T: DeserializeOwned>(data: &str, defaults: Option<&toml::Table>) -> anyhow::Result<T> {
+    let mut table: toml::Table = toml::from_str(data)?;
+    table.remove("defaults");
+    if let Some(defaults) = defaults {
+        apply_site_defaults(&mut table, defaults)?;
+    }
+    let expanded = expand_env_vars(&mut table)?;
+
+    if defaults.is_none() && !expanded {
+        // Parsing directly keeps the location of errors
+        return Ok(toml::from_str(data)?);
+    }
+    Ok(T::deserialize(toml::Value::Table(table))?)
+}
+
+/// Fields whose values use `${...}` for their own variables
+const ENV_EXPANSION_EXCLUDED_FIELDS: &[&str] = &["access_log_format"];
+
+/// Replaces `${VAR}` and `${VAR:-default}` in the string values of a parsed config file by environment variables.
+/// `$${` is kept as a literal `${`. Returns whether anything was replaced.
+fn expand_env_vars(table: &mut toml::Table) -> anyhow::Result<bool> {
+    fn expand_value(key: &str, value: &mut toml::Value) -> anyhow::Result<bool> {
+        match value {
+            toml::Value::String(string) => match expand_env_string(string).map_err(|e| anyhow!("{key}: {e}"))? {
+                Some(expanded) => {
+                    *string = expanded;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            toml::Value::Array(values) => values.iter_mut().try_fold(false, |expanded, value| Ok(expand_value(key, value)? || expanded)),
+            toml::Value::Table(table) => expand_env_vars(table),
+            _ => Ok(false),
+        }
+    }
+
+    let mut expanded = false;
+    for (key, value) in table.iter_mut() {
+        if !ENV_EXPANSION_EXCLUDED_FIELDS.contains(&key.as_str()) {
+            expanded |= expand_value(key, value)?;
+        }
+    }
+    Ok(expanded)
+}
+
+/// Expands the environment variables of a string, or returns `None` if it doesn't contain any
+fn expand_env_string(value: &str) -> anyhow::Result<Option<String>> {
+    if !value.contains("${") {
+        return Ok(None);
+    }
+
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            // The first $ is kept, and the second one dropped
+            expanded.push_str(&rest[..start]);
+            expanded.push('{');
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+
+        let end = rest[start..].find('}').ok_or(anyhow!("unclosed ${{ in {value:?}"))?;
+        let expression = &rest[start + 2..start + end];
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expression, None),
+        };
+        match (std::env::var(name), default) {
+            (Ok(variable), _) => expanded.push_str(&variable),
+            (Err(_), Some(default)) => expanded.push_str(default),
+            (Err(_), None) => bail!("environment variable {name} is not set (use ${{{name}:-default}} to provide a default)"),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(Some(expanded))
+}
+
+/// Adds the sites defined in the `.toml` files of `include_dir`, in file name order
+fn load_included_sites(config: &mut Config, config_path: &Path, defaults: Option<&toml::Table>) -> anyhow::Result<()> {
+    let Some(include_dir) = &config.top_level.include_dir else {
+        return Ok(());
+    };
+
+    // Relative paths are relative to the main config file
+    let include_dir = match config_path.parent() {
+        Some(parent) => parent.join(include_dir),
+        None => PathBuf::from(include_dir),
+    };
+
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(&include_dir).map_err(|e| anyhow!("could not read include_dir {}: {e}", include_dir.display()))? {
+        let path = entry.map_err(|e| anyhow!("could not read include_dir {}: {e}", include_dir.display()))?.path();
+        if path.extension().is_some_and(|extension| extension == "toml") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut origins: Vec<(String, PathBuf)> = config.sites.iter().map(|site_config| (site_config.name.clone(), config_path.to_path_buf())).collect();
+    for path in paths {
+        check_config_file_permissions(&path)?;
+        let data = std::fs::read_to_string(&path).map_err(|e| anyhow!("could not read config file {}: {e}", path.display()))?;
+        let included: IncludedConfig = parse_config_file(&data, defaults).map_err(|e| anyhow!("could not parse config file {}: {e}", path.display()))?;
+
+        for site_config in included.sites {
+            if let Some((_, origin)) = origins.iter().find(|(name, _)| *name == site_config.name) {
+                bail!("Site name {} is defined in both {} and {}", site_config.name, origin.display(), path.display());
+            }
+            origins.push((site_config.name.clone(), path.clone()));
+            config.sites.push(site_config);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads, parses and validates the config.
+pub fn load_config(source: &ConfigSource) -> anyhow::Result<Config> {
+    let config_data = source.read()?;
+    let table: toml::Table = toml::from_str(&config_data).map_err(|e| anyhow!("could not parse config file: {e}"))?;
+    let defaults = match table.get("defaults") {
+        Some(toml::Value::Table(defaults)) => Some(defaults),
+        Some(_) => bail!("defaults must be a table"),
+        None => None,
+    };
+    let mut config: Config = parse_config_file(&config_data, defaults).map_err(|e| anyhow!("could not parse config file: {e}"))?;
+    load_included_sites(&mut config, source.path(), defaults)?;
+    expand_routes(&mut config);
+    compile_path_patterns(&mut config)?;
+    clamp_keep_alive(&mut config);
+    validate_config(&config)?;
+
+    Ok(config)
+}
+
+/// Adds a site for each route, after the sites
+fn expand_routes(config: &mut Config) {
+    let mut route_sites = Vec::new();
+    for site_config in &config.sites {
+        for route in &site_config.routes {
+            let mut route_site = site_config.clone();
+            route_site.name = route.name.clone();
+            route_site.port = Some(route.port);
+            route_site.upstream_socket = None;
+            route_site.service_name = route.service_name.clone();
+            route_site.nginx_available_config = route.nginx_available_config.clone();
+            route_site.nginx_enabled_config = route.nginx_enabled_config.clone();
+            route_site.nginx_hibernator_config = route.nginx_hibernator_config.clone();
+            route_site.routes = Vec::new();
+            route_site.route_paths = Some(route.paths.clone());
+            route_sites.push(route_site);
+        }
+    }
+    config.sites.extend(route_sites);
+}
+
+fn compile_path_patterns(config: &mut Config) -> anyhow::Result<()> {
+    for site_config in &mut config.sites {
+        let mode = site_config.path_match_mode;
+        let lists = [
+            ("path_blacklist", &mut site_config.path_blacklist),
+            ("wake_path_whitelist", &mut site_config.wake_path_whitelist),
+            ("route paths", &mut site_config.route_paths),
+        ];
+        for (list_name, patterns) in lists {
+            for pattern in patterns.iter_mut().flatten() {
+                pattern.compile(mode).map_err(|e| anyhow!(
+                    "Site {} has an invalid {mode:?} pattern {:?} in {list_name}: {e}", site_config.name, pattern.as_str()
+                ))?;
+            }
+        }
+        for route in &mut site_config.routes {
+            for pattern in &mut route.paths {
+                pattern.compile(mode).map_err(|e| anyhow!(
+                    "Route {} of site {} has an invalid {mode:?} pattern {:?}: {e}", route.name, site_config.name, pattern.as_str()
+                ))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn clamp_keep_alive(config: &mut Config) {
+    let min_keep_alive = config.top_level.min_keep_alive();
+    for site_config in &mut config.sites {
+        // A keep_alive of 0 would stop sites right after they start, so it means never stopping them
+        if site_config.keep_alive == 0 {
+            site_config.keep_alive = KEEP_ALIVE_NEVER;
+            continue;
+        }
+        if site_config.keep_alive < min_keep_alive {
+            warn!(
+                "Site {} has a keep_alive of {}s, which is below the minimum of {min_keep_alive}s. Using {min_keep_alive}s instead",
+                site_config.name, site_config.keep_alive
+            );
+            site_config.keep_alive = min_keep_alive;
+        }
+    }
+}
+
+fn is_non_public_address(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => address.is_loopback() || address.is_private(),
+        IpAddr::V6(address) => address.is_loopback() || (address.segments()[0] & 0xfe00) == 0xfc00, // Unique local
+    }
+}
+
+pub fn validate_config(config: &Config) -> anyhow::Result<()> {
+    // Make sure the hibernator doesn't listen on a public interface
+    let bind_address = config.top_level.bind_address();
+    let bind_address: IpAddr = bind_address.parse().map_err(|e| anyhow!("Invalid bind_address {bind_address}: {e}"))?;
+    if !is_non_public_address(bind_address) && !config.top_level.i_know_this_is_dangerous {
+        bail!("bind_address {bind_address} is not a loopback or private address. Set i_know_this_is_dangerous = true if this is intended");
+    }
+
+    // Make sure the API token isn't blank
+    if config.top_level.api_token.as_ref().is_some_and(|token| token.trim().is_empty()) {
+        bail!("api_token cannot be empty");
+    }
+
+    // Make sure the check jitter stays reasonable
+    if config.top_level.check_jitter_ms() > 3_600_000 {
+        bail!("check_jitter_ms cannot exceed one hour");
+    }
+
+    // Make sure the memory high-water mark can be reached
+    if let Some(memory_pressure) = &config.top_level.memory_pressure {
+        if memory_pressure.max_used_percent == 0 || memory_pressure.max_used_percent >= 100 {
+            bail!("memory_pressure.max_used_percent must be between 1 and 99");
+        }
+        if memory_pressure.check_interval() == 0 {
+            bail!("memory_pressure.check_interval cannot be 0");
+        }
+    }
+
+    // Make sure the access log format only uses known variables
+    crate::request_log::validate_format(config.top_level.access_log_format())?;
+
+    // Make sure the unknown site response is valid
+    if !(100..=599).contains(&config.top_level.unknown_site_status()) {
+        bail!("unknown_site_status must be a valid HTTP status code");
+    }
+    if let Some(body_file) = &config.top_level.unknown_site_body_file {
+        if config.top_level.unknown_site_body.is_some() {
+            bail!("unknown_site_body and unknown_site_body_file cannot both be set");
+        }
+        if !Path::new(body_file).is_file() {
+            bail!("unknown_site_body_file {body_file} does not exist");
+        }
+    }
+
+    // Make sure the GeoIP database exists
+    if let Some(geoip_database) = &config.top_level.geoip_database {
+        if !Path::new(geoip_database).is_file() {
+            bail!("geoip_database {geoip_database} does not exist");
+        }
+    }
+
+    // Make sure the API prefix is a usable path prefix
+    let api_prefix = config.top_level.api_prefix();
+    if !api_prefix.starts_with('/') || api_prefix.ends_with('/') {
+        bail!("api_prefix {api_prefix:?} must start with a slash and not end with one");
+    }
+
+    // Make sure the client IP header is a valid header name
+    if let Some(header) = &config.top_level.trusted_proxy_ip_header {
+        if header.is_empty() || !header.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            bail!("trusted_proxy_ip_header {header:?} is not a valid header name");
+        }
+    }
+
+    // Make sure site names are unique
+    let mut names = HashSet::new();
+    for site_config in &config.sites {
+        if !names.insert(&site_config.name) {
+            bail!("Site name {} is used more than once", site_config.name);
+        }
+    }
+
+    // Make sure every site has exactly one upstream
+    for site_config in &config.sites {
+        match (site_config.port, &site_config.upstream_socket) {
+            (Some(_), Some(_)) => bail!("Site {} cannot have both port and upstream_socket", site_config.name),
+            (None, None) => bail!("Site {} must have either port or upstream_socket", site_config.name),
+            _ => (),
+        }
+    }
+
+    // Make sure every access log exists
+    for site_config in &config.sites {
+        if !Path::new(&site_config.access_log).exists() {
+            bail!("Site {} access log doesn't exist at {}", site_config.name, site_config.access_log);
+        }
+    }
+
+    // Make sure every hibernator config exists
+    for site_config in &config.sites {
+        if !Path::new(&site_config.nginx_hibernator_config()).exists() {
+            bail!("Site {} hibernator config doesn't exist at {}", site_config.name, site_config.nginx_hibernator_config());
+        }
+    }
+
+    // Make sure every site has at least one host
+    for site_config in &config.sites {
+        if site_config.hosts.is_empty() {
+            bail!("Site {} must have at least one host", site_config.name);
+        }
+    }
+
+    // Make sure systemctl can be invoked
+    for site_config in &config.sites {
+        if site_config.systemctl_prefix().trim().is_empty() {
+            bail!("Site {} systemctl_prefix must not be empty", site_config.name);
+        }
+    }
+
+    // Make sure nginx can be reloaded and checked
+    for site_config in &config.sites {
+        if site_config.nginx_reload_argv(config).is_empty() || site_config.nginx_test_argv(config).is_empty() {
+            bail!("Site {} nginx_reload_command and nginx_test_command must not be empty", site_config.name);
+        }
+    }
+
+    // Make sure every route has paths
+    for site_config in &config.sites {
+        for route in &site_config.routes {
+            if route.paths.is_empty() {
+                bail!("Route {} of site {} must have at least one path", route.name, site_config.name);
+            }
+        }
+    }
+
+    // Make sure a site doesn't have blacklist_ips and whitelist_ips at the same time
+    for site_config in &config.sites {
+        if site_config.ip_blacklist.is_some() && site_config.ip_whitelist.is_some() {
+            bail!("Site {} cannot have both blacklist_ips and whitelist_ips", site_config.name);
+        }
+    }
+
+    // Make sure the whitelists are not empty if they exist
+    for site_config in &config.sites {
+        if let Some(whitelist_ips) = &site_config.ip_whitelist {
+            if whitelist_ips.is_empty() {
+                bail!("Site {} whitelist_ips cannot be empty", site_config.name);
+            }
+        }
+    }
+
+    // Make sure upstream connections have time to be established
+    for site_config in &config.sites {
+        if site_config.upstream_connect_timeout_ms.0 == 0 {
+            bail!("Site {} upstream_connect_timeout_ms must be greater than 0", site_config.name);
+        }
+        if site_config.health_check_timeout_ms.0 == 0 {
+            bail!("Site {} health_check_timeout_ms must be greater than 0", site_config.name);
+        }
+    }
+
+    // Make sure wake concurrency limits let requests through
+    for site_config in &config.sites {
+        if site_config.wake_concurrency == Some(0) {
+            bail!("Site {} wake_concurrency cannot be 0", site_config.name);
+        }
+    }
+
+    // Make sure the expected health check strings are not empty
+    for site_config in &config.sites {
+        if site_config.health_check_expect_body_contains.as_deref() == Some("") {
+            bail!("Site {} health_check_expect_body_contains cannot be empty", site_config.name);
+        }
+        if site_config.health_check_expect_body_contains.is_some() && site_config.health_check_mode == HealthCheckMode::Connect {
+            bail!("Site {} cannot expect a response body with health_check_mode = \"connect\"", site_config.name);
+        }
+    }
+
+    // Make sure the readiness quorums can be reached
+    for site_config in &config.sites {
+        let probes = site_config.readiness_ports.len() + 1;
+        if site_config.readiness_quorum == Some(0) || site_config.readiness_quorum() > probes {
+            bail!("Site {} readiness_quorum must be between 1 and {probes}", site_config.name);
+        }
+    }
+
+    // Make sure the proxied headers can be set safely
+    for site_config in &config.sites {
+        for name in site_config.proxy_set_headers.keys() {
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+                bail!("Site {} has an invalid header name {name:?} in proxy_set_headers", site_config.name);
+            }
+            if ["host", "content-length", "transfer-encoding", "connection"].contains(&name.to_lowercase().as_str()) {
+                bail!("Site {} cannot set {name} in proxy_set_headers", site_config.name);
+            }
+        }
+    }
+
+    // Make sure the health check thresholds can be reached
+    for site_config in &config.sites {
+        if site_config.health_check_success_threshold == Some(0) {
+            bail!("Site {} health_check_success_threshold must be at least 1", site_config.name);
+        }
+    }
+
+    // Make sure the wake path whitelists are not empty and don't overlap with the path blacklists
+    for site_config in &config.sites {
+        if let Some(wake_path_whitelist) = &site_config.wake_path_whitelist {
+            if wake_path_whitelist.is_empty() {
+                bail!("Site {} wake_path_whitelist cannot be empty", site_config.name);
+            }
+            for pattern in site_config.path_blacklist.iter().flatten() {
+                let overlaps = |wake_pattern: &PathPattern| {
+                    wake_pattern.as_str() == pattern.as_str() || wake_pattern.is_match(pattern.as_str()) || pattern.is_match(wake_pattern.as_str())
+                };
+                if wake_path_whitelist.iter().any(overlaps) {
+                    bail!("Site {} has {} in path_blacklist, which wake_path_whitelist also matches", site_config.name, pattern.as_str());
+                }
+            }
+        }
+    }
+
+    // Make sure every site has an index.html in its landing folder
+    for site_config in &config.sites {
+        let landing_folder = site_config.landing_folder(config);
+        let index_path = Path::new(landing_folder).join("index.html");
+        if !index_path.exists() {
+            bail!(
+                "Site {} landing page doesn't exist at {:?}. Expected index.html in landing folder: {}",
+                site_config.name, index_path, landing_folder
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+    use super::*;
+
+    #[test]
+    fn short_keep_alive_is_raised_to_the_minimum() {
+        let env = TestEnv::new();
+        let config = env.load("min_keep_alive = \"1m\"", vec![env.site("clamped", free_port(), "keep_alive = 5")]);
+        assert_eq!(config.sites[0].keep_alive, 60);
+        assert!(logs().iter().any(|line| line == "WARN Site clamped has a keep_alive of 5s, which is below the minimum of 60s. Using 60s instead"));
+    }
+
+    #[test]
+    fn symlinked_config_is_checked_through_its_target() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("config.toml");
+        let link = dir.path().join("link.toml");
+        std::fs::write(&target, "").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+        symlink(&target, &link).unwrap();
+        check_config_file_permissions(&link).unwrap();
+
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o666)).unwrap();
+        let error = check_config_file_permissions(&link).unwrap_err().to_string();
+        assert!(error.contains(&target.display().to_string()), "{error}");
+    }
+
+    #[test]
+    fn database_map_size_is_rounded_to_pages() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let top_level: TopLevelConfig = toml::from_str("database_map_size = \"1000\"").unwrap();
+        assert_eq!(top_level.database_map_size(), page_size);
+        let top_level: TopLevelConfig = toml::from_str("database_map_size = \"512MiB\"").unwrap();
+        assert_eq!(top_level.database_map_size(), 512 << 20);
+    }
+
+    #[test]
+    fn wake_paths_overlapping_the_blacklist_are_rejected() {
+        let env = TestEnv::new();
+        let site = env.site("overlap", free_port(), "path_blacklist = [\"/app/admin\"]\nwake_path_whitelist = [\"/app/*\"]");
+        let path = env.write_config("", vec![site]);
+        let error = load_config(&ConfigSource::File(path)).unwrap_err().to_string();
+        assert_eq!(error, "Site overlap has /app/admin in path_blacklist, which wake_path_whitelist also matches");
+
+        let site = env.site("overlap", free_port(), "path_blacklist = [\"/static/*\"]\nwake_path_whitelist = [\"/app/*\"]");
+        load_config(&ConfigSource::File(env.write_config("", vec![site]))).unwrap();
+    }
+
+    #[test]
+    fn durations_are_parsed_with_units() {
+        assert_eq!(parse_duration_ms("90s", 1000), Ok(90_000));
+        assert_eq!(parse_duration_ms("1h30m", 1000), Ok(5_400_000));
+        assert_eq!(parse_duration_ms("2d", 1000), Ok(172_800_000));
+        assert_eq!(parse_duration_ms("300", 1000), Ok(300_000));
+        assert_eq!(parse_duration_ms("5w", 1000), Err(String::from("unknown unit \"w\" in duration \"5w\"")));
+    }
+
+    #[test]
+    fn sub_unit_durations_are_rounded_up() {
+        #[derive(Deserialize)]
+        struct Durations {
+            #[serde(deserialize_with = "deserialize_duration")]
+            seconds: u64,
+        }
+
+        let durations: Durations = toml::from_str("seconds = \"500ms\"").unwrap();
+        assert_eq!(durations.seconds, 1);
+        let durations: Durations = toml::from_str("seconds = \"2s\"").unwrap();
+        assert_eq!(durations.seconds, 2);
+    }
+
+    #[test]
+    fn zero_keep_alive_means_never() {
+        let env = TestEnv::new();
+        let config = env.load("min_keep_alive = \"1m\"", vec![env.site("zero", free_port(), "keep_alive = 0")]);
+        assert_eq!(config.sites[0].keep_alive, KEEP_ALIVE_NEVER);
+        assert!(!config.sites[0].hibernates());
+    }
+}
@@ -1,91 +1,330 @@
-use std::{cmp::max, time::Duration};
+use std::{cmp::{max, min}, io::Read, os::unix::fs::MetadataExt, sync::{atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering}, Mutex, OnceLock, RwLock}, time::Duration};
 
 use chrono::{DateTime, Utc};
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use log::*;
 use serde::{Serialize, Deserialize};
-use tokio::{fs::read_to_string, sync::{broadcast::{Receiver as BroadReceiver, Sender as BroadSender}, mpsc::{Receiver, Sender}}, time::{sleep, Instant}};
-use crate::{checking_symlink, database::DATABASE, is_healthy, run_command, SiteConfig};
+use flate2::read::GzDecoder;
+use tokio::{fs::{metadata, read, read_link, read_to_string}, spawn, sync::{broadcast::{Receiver as BroadReceiver, Sender as BroadSender}, mpsc::{Receiver, Sender}, Notify, Semaphore, SemaphorePermit}, task::{spawn_blocking, AbortHandle}, time::{sleep, Instant}};
+use crate::{checking_symlink, database::{SiteCounters, DATABASE}, shutdown::shutdown_requested, webhook::notify_state_change, is_healthy, is_ready, parse_ip, random_u64, run_command_output, run_program, config::current_config, SiteConfig};
+
+/// Number of consecutive failed starts after which further starts get delayed
+const START_FAILURES_BEFORE_BACKOFF: u32 = 3;
+/// Delay applied after reaching [`START_FAILURES_BEFORE_BACKOFF`], doubled for each additional failure
+const START_BACKOFF_BASE: Duration = Duration::from_secs(30);
+const START_BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+/// How often an access log error that keeps happening is logged again
+const PARSE_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub struct SiteController {
     pub config: &'static SiteConfig,
-    start_sender: Sender<()>,
-    started_receiver: BroadReceiver<()>
+    start_sender: Sender<TriggerSource>,
+    /// Receives the state of the site after each start attempt
+    started_receiver: BroadReceiver<SiteState>,
+    task: OnceLock<AbortHandle>,
+    /// Set while the site is being marked as up and nginx is being switched back to the site config
+    up_transition: AtomicBool,
+    /// Number of consecutive starts that failed
+    failed_starts: AtomicU32,
+    /// When the last start failed, as a timestamp in milliseconds
+    last_failed_start: AtomicI64,
+    /// When the site last became up, as a timestamp in milliseconds
+    up_since: AtomicI64,
+    /// When the site was last active according to its access log, as a timestamp in milliseconds
+    last_activity: AtomicI64,
+    /// Notified when the site should be stopped regardless of its activity
+    stop_requested: Notify,
+    /// Requests currently waiting for the site to start
+    waiters: AtomicU32,
+    /// Requests that were waiting when the site last became ready, and the most ever seen
+    last_wake_waiters: AtomicU32,
+    max_wake_waiters: AtomicU32,
+    /// Requests proxied during the current wake cycle, and during the previous one
+    cycle_requests: AtomicU64,
+    last_cycle_requests: AtomicU64,
+    /// Error of the last nginx config switch
+    nginx_error: RwLock<Option<String>>,
+    /// Error of the last access log check, and when it was last logged
+    parse_error: Mutex<Option<(String, Instant)>>,
+    /// Error reading the access log file, set while it's missing or unreadable
+    access_log_error: RwLock<Option<String>>,
+    /// Limits health probes made by requests while the site is waking up
+    wake_semaphore: Option<Semaphore>,
+    /// Inode and size of the access log when it was last read, to detect rotations
+    access_log_seen: Mutex<Option<(u64, u64)>>,
+    /// Lifetime counters, kept in memory so that they are cheap to read and persisted on every change
+    counters: Mutex<SiteCounters>,
 }
 
 impl SiteController {
-    pub async fn new(config: &'static SiteConfig) -> (Self, Receiver<()>, BroadSender<()>) {
+    pub async fn new(config: &'static SiteConfig) -> (Self, Receiver<TriggerSource>, BroadSender<SiteState>) {
         let (start_sender, start_receiver) = tokio::sync::mpsc::channel(1);
         let (started_sender, started_receiver) = tokio::sync::broadcast::channel(1);
 
-        DATABASE.update_state(&config.name, SiteState::Unknown).expect("could not set initial site state in database");
+        if let Err(e) = DATABASE.update_state(&config.name, SiteState::Unknown) {
+            error!("Couldn't record initial state of {}: {e}", config.name);
+        }
+        let counters = DATABASE.get_site_counters(&config.name).unwrap_or_else(|e| {
+            error!("Couldn't read counters of {}: {e}", config.name);
+            SiteCounters::default()
+        });
 
         (Self {
             config,
             start_sender,
-            started_receiver
+            started_receiver,
+            task: OnceLock::new(),
+            up_transition: AtomicBool::new(false),
+            failed_starts: AtomicU32::new(0),
+            last_failed_start: AtomicI64::new(0),
+            up_since: AtomicI64::new(0),
+            last_activity: AtomicI64::new(0),
+            stop_requested: Notify::new(),
+            waiters: AtomicU32::new(0),
+            last_wake_waiters: AtomicU32::new(0),
+            max_wake_waiters: AtomicU32::new(0),
+            cycle_requests: AtomicU64::new(0),
+            last_cycle_requests: AtomicU64::new(0),
+            nginx_error: RwLock::new(None),
+            parse_error: Mutex::new(None),
+            access_log_error: RwLock::new(None),
+            wake_semaphore: config.wake_concurrency.map(Semaphore::new),
+            access_log_seen: Mutex::new(None),
+            counters: Mutex::new(counters),
         }, start_receiver, started_sender)
     }
 
-    pub fn trigger_start(&self) {
-        let _ = self.start_sender.try_send(()); // We don't care about the error because if this fails, that means the site was already requested to be started
+    /// Creates a controller for the site and spawns its task.
+    pub async fn spawn(config: &'static SiteConfig) -> &'static SiteController {
+        let (controller, start_receiver, started_sender) = SiteController::new(config).await;
+        let controller: &'static SiteController = Box::leak(Box::new(controller));
+
+        let handle = spawn(controller.handle(start_receiver, started_sender));
+        let _ = controller.task.set(handle.abort_handle());
+
+        controller
+    }
+
+    /// Stops the controller task. The site won't be managed anymore.
+    pub fn abort(&self) {
+        if let Some(task) = self.task.get() {
+            task.abort();
+        }
+    }
+
+    pub fn trigger_start(&self, source: TriggerSource) {
+        let _ = self.start_sender.try_send(source); // We don't care about the error because if this fails, that means the site was already requested to be started
+    }
+
+    /// Asks the site to be stopped now, even if it's still within its keep_alive.
+    /// Sites that aren't up or are kept up by `min_uptime` ignore it.
+    pub fn request_stop(&self) {
+        self.stop_requested.notify_one();
+    }
+
+    /// When the site was last active, or became up if its activity wasn't checked since.
+    pub fn last_activity(&self) -> Option<DateTime<Utc>> {
+        let last_activity = max(self.last_activity.load(Ordering::Relaxed), self.up_since.load(Ordering::Relaxed));
+        DateTime::from_timestamp_millis(last_activity).filter(|_| last_activity > 0)
     }
 
-    pub async fn waiting_trigger_start(&self) {
-        self.trigger_start();
+    /// Starts the site and waits for the attempt to finish.
+    /// Fails if the site didn't come up, so that callers don't wait for a dead upstream.
+    pub async fn waiting_trigger_start(&self, source: TriggerSource) -> anyhow::Result<()> {
+        if self.get_state().is_up() {
+            return Ok(());
+        }
+
+        // Subscribe before triggering so that the start completing in between isn't missed
         let mut started_receiver = self.started_receiver.resubscribe();
-        let _ = started_receiver.recv().await;
+        let _waiter = WaiterGuard::new(&self.waiters);
+        self.trigger_start(source);
+        match started_receiver.recv().await {
+            Ok(SiteState::Up | SiteState::Starting) | Err(_) => Ok(()),
+            Ok(state) => Err(anyhow!("site {} did not start (state: {})", self.config.name, state.as_str())),
+        }
     }
 
-    async fn on_down(&self) {
-        let r = checking_symlink(&self.config.nginx_hibernator_config(), &self.config.nginx_enabled_config()).await;
-        let r = match r {
-            Ok(true) => run_command("nginx -s reload").await,
-            Ok(false) => Ok(()),
-            Err(e) => {
-                error!("Error while checking nginx symlink for {}: {e}", self.config.name);
-                Ok(())
-            }
-        };
+    /// Counts a request proxied to the site towards the current wake cycle
+    pub fn record_served_request(&self) {
+        self.cycle_requests.fetch_add(1, Ordering::Relaxed);
+    }
 
-        if let Err(e) = r {
-            error!("Error while reloading nginx for {}: {e}", self.config.name);
+    pub fn wake_stats(&self) -> WakeStats {
+        WakeStats {
+            current_waiters: self.waiters.load(Ordering::Relaxed),
+            last_wake_waiters: self.last_wake_waiters.load(Ordering::Relaxed),
+            max_wake_waiters: self.max_wake_waiters.load(Ordering::Relaxed),
+            current_cycle_requests: self.cycle_requests.load(Ordering::Relaxed),
+            last_cycle_requests: self.last_cycle_requests.load(Ordering::Relaxed),
         }
     }
 
+    /// Records how many requests were held while the site was starting, as it just became ready
+    fn record_wake_waiters(&self) {
+        let waiters = self.waiters.load(Ordering::Relaxed);
+        self.last_wake_waiters.store(waiters, Ordering::Relaxed);
+        self.max_wake_waiters.fetch_max(waiters, Ordering::Relaxed);
+    }
+
+    /// Points the enabled nginx config to `config` and reloads nginx.
+    /// The symlink is reverted to `fallback` if nginx rejects the resulting config.
+    async fn switch_nginx_config(&self, config: &str, fallback: &str) -> anyhow::Result<()> {
+        let enabled_config = self.config.nginx_enabled_config();
+        let hibernator_config = current_config();
+        let test_argv = self.config.nginx_test_argv(hibernator_config);
+
+        // nginx doesn't need to be checked when it already uses the right config
+        if read_link(&enabled_config).await.is_ok_and(|link| link.to_str() == Some(config)) {
+            return Ok(());
+        }
+
+        run_program(&test_argv).await.map_err(|e| anyhow!("nginx config is invalid before switching: {e}"))?;
+        if !checking_symlink(config, &enabled_config).await? {
+            return Ok(());
+        }
+
+        if let Err(e) = run_program(&test_argv).await {
+            checking_symlink(fallback, &enabled_config).await.map_err(|e| anyhow!("could not revert nginx symlink: {e}"))?;
+            return Err(anyhow!("nginx rejected {config}, reverted to {fallback}: {e}"));
+        }
+
+        run_program(&self.config.nginx_reload_argv(hibernator_config)).await
+    }
+
+    async fn update_nginx_config(&self, config: &str, fallback: &str) {
+        let r = self.switch_nginx_config(config, fallback).await;
+        if let Err(e) = &r {
+            error!("Error while switching nginx config for {}: {e}", self.config.name);
+        }
+        *self.nginx_error.write().expect("nginx error lock poisoned") = r.err().map(|e| e.to_string());
+    }
+
+    /// Waits for the right to probe the upstream while the site is waking up.
+    /// Returns immediately if the number of concurrent attempts isn't limited.
+    pub async fn acquire_wake_permit(&self) -> Option<SemaphorePermit<'_>> {
+        self.wake_semaphore.as_ref()?.acquire().await.ok()
+    }
+
+    async fn on_down(&self) {
+        self.update_nginx_config(&self.config.nginx_hibernator_config(), &self.config.nginx_available_config()).await;
+    }
+
     async fn on_up(&self) {
         info!("Reloading nginx for {}", self.config.name);
-        let should_reload = checking_symlink(&self.config.nginx_available_config(), &self.config.nginx_enabled_config()).await;
-        let should_reload = match should_reload {
-            Ok(should_reload) => should_reload,
-            Err(e) => {
-                error!("Error while checking nginx symlink for {}: {e}", self.config.name);
+        self.update_nginx_config(&self.config.nginx_available_config(), &self.config.nginx_hibernator_config()).await;
+    }
+
+    /// The error of the last nginx config switch, if it failed
+    pub fn nginx_error(&self) -> Option<String> {
+        self.nginx_error.read().expect("nginx error lock poisoned").clone()
+    }
+
+    /// The error of the last access log check, if it failed.
+    /// While there is one, the site is kept up.
+    pub fn parse_error(&self) -> Option<String> {
+        self.parse_error.lock().expect("parse error lock poisoned").as_ref().map(|(error, _)| error.clone())
+    }
+
+    pub fn access_log_error(&self) -> Option<String> {
+        self.access_log_error.read().expect("access log error lock poisoned").clone()
+    }
+
+    /// Records the outcome of an access log check, telling apart a log that can't be read from one that can't be parsed.
+    fn record_check_result(&self, result: Result<(), &anyhow::Error>) {
+        let read_error = result.err().filter(|err| err.downcast_ref::<std::io::Error>().is_some());
+        let mut access_log_error = self.access_log_error.write().expect("access log error lock poisoned");
+        match (read_error, access_log_error.is_some()) {
+            (Some(err), false) => warn!(
+                "Access log {} of site {} can't be read, the site won't be hibernated until it can: {err:#}",
+                self.config.access_log, self.config.name
+            ),
+            (None, true) => info!("Access log {} of site {} can be read again", self.config.access_log, self.config.name),
+            _ => (),
+        }
+        *access_log_error = read_error.map(|err| format!("{err:#}"));
+        drop(access_log_error);
+
+        if read_error.is_none() {
+            self.record_parse_result(result);
+        }
+    }
+
+    /// Records the outcome of an access log check.
+    /// An error is logged when it first happens, and then only once per [`PARSE_ERROR_LOG_INTERVAL`] while it persists.
+    fn record_parse_result(&self, result: Result<(), &anyhow::Error>) {
+        let mut parse_error = self.parse_error.lock().expect("parse error lock poisoned");
+        let err = match result {
+            Ok(()) => {
+                if parse_error.take().is_some() {
+                    info!("Access log of {} can be checked again", self.config.name);
+                }
                 return;
             }
+            Err(err) => err.to_string(),
         };
-        if should_reload {
-            let r = run_command("nginx -s reload").await;
-            if let Err(e) = r {
-                error!("Error while reloading nginx for {}: {e}", self.config.name);
+
+        match parse_error.as_mut() {
+            Some((previous, last_logged)) if *previous == err && last_logged.elapsed() < PARSE_ERROR_LOG_INTERVAL => {
+                debug!("Error while checking site {} (already reported): {err}", self.config.name);
+            }
+            _ => {
+                error!("Error while checking site {}, keeping it up until this is fixed: {err}", self.config.name);
+                *parse_error = Some((err, Instant::now()));
             }
         }
     }
 
     async fn set_state(&self, state: SiteState) {
         let old_state = self.get_state();
-        DATABASE.update_state(&self.config.name, state).expect("could not update site state in database");
+        if state == SiteState::Up && old_state != SiteState::Up {
+            self.up_transition.store(true, Ordering::Relaxed);
+            self.up_since.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        }
+        if let Err(e) = DATABASE.update_state(&self.config.name, state) {
+            error!("Couldn't record that {} is {}: {e}", self.config.name, state.as_str());
+        }
 
         if old_state == state {
             return;
         }
+        info!(site = self.config.name.as_str(), state = state.as_str(), event = "state_change"; "Site {} is now {}", self.config.name, state.as_str());
+        if let Some(url) = &self.config.state_change_webhook {
+            notify_state_change(url, &self.config.name, old_state, state);
+        }
+
+        let counters = {
+            let mut counters = self.counters.lock().expect("counters lock poisoned");
+            counters.record(old_state, state, Utc::now());
+            *counters
+        };
+        if let Err(e) = DATABASE.put_site_counters(&self.config.name, &counters) {
+            error!("Couldn't save counters of {}: {e}", self.config.name);
+        }
 
         match state {
-            SiteState::Down => self.on_down().await,
-            SiteState::Up => self.on_up().await,
+            SiteState::Down | SiteState::Stopping => self.on_down().await,
+            SiteState::Up => {
+                self.on_up().await;
+                self.up_transition.store(false, Ordering::Relaxed);
+            },
             _ => ()
         }
     }
 
+    /// Whether requests can be proxied to the site right away.
+    /// The site is recorded as up before nginx is switched back to it,
+    /// so requests that keep reaching the hibernator until the nginx reload completes get proxied too.
+    pub fn is_serving(&self) -> bool {
+        self.get_state().is_up()
+    }
+
+    /// Whether nginx is still being switched back to the site after it became up.
+    /// Requests that would normally be ignored are proxied during that window, as nginx would have sent them to the site.
+    pub fn is_switching_to_up(&self) -> bool {
+        self.up_transition.load(Ordering::Relaxed)
+    }
+
     pub fn get_state(&self) -> SiteState {
         DATABASE.get_last_state(&self.config.name).map(|(state, _)| state).unwrap_or(SiteState::Unknown)
     }
@@ -108,9 +347,10 @@ impl SiteController {
             trace!("Site was not starting");
             last_changed = Utc::now();
         }
+        // A state change recorded in the future due to clock skew counts as just started
         let done = (now - last_changed).to_std().unwrap_or_default();
 
-        let duration_estimate = match DATABASE.get_start_duration_estimate(&self.config.name, self.config.eta_percentile.0) {
+        let duration_estimate = match DATABASE.get_start_duration_estimate(&self.config.name, self.config.eta_sample_size.0, self.config.eta_percentile.0) {
             Ok(duration_estimate) => duration_estimate,
             Err(e) => {
                 warn!("Couldn't get duration estimate: {e}");
@@ -118,15 +358,65 @@ impl SiteController {
             }
         };
 
-        Some((done, duration_estimate))
+        Some((min(done, duration_estimate), duration_estimate))
+    }
+
+    /// Reads the access log.
+    /// If it was rotated since the last read, the most recent rotated log is read as well and comes first.
+    async fn read_access_log(&self) -> anyhow::Result<String> {
+        let access_log = &self.config.access_log;
+        let metadata = metadata(access_log).await.context("could not stat access log")?;
+        let seen = (metadata.ino(), metadata.size());
+        let previously_seen = self.access_log_seen.lock().expect("access log lock poisoned").replace(seen);
+        let content = read_to_string(access_log).await.context("could not read access log")?;
+
+        let rotated = match previously_seen {
+            Some((inode, size)) => inode != seen.0 || size > seen.1,
+            None => false,
+        };
+        if !rotated && !content.is_empty() {
+            return Ok(content);
+        }
+
+        let rotated_log = format!("{access_log}{}", self.config.log_rotation_suffix());
+        let rotated_content = match read_to_string(&rotated_log).await {
+            Ok(rotated_content) => rotated_content,
+            Err(_) => match read(format!("{rotated_log}.gz")).await {
+                // Decompression can take a while for big logs, so it's kept off the runtime
+                Ok(compressed) => spawn_blocking(move || {
+                    let mut rotated_content = String::new();
+                    GzDecoder::new(compressed.as_slice()).read_to_string(&mut rotated_content).map(|_| rotated_content)
+                }).await?.map_err(|e| anyhow!("could not decompress rotated access log: {e}"))?,
+                Err(_) => {
+                    trace!("No rotated access log found for {}", self.config.name);
+                    return Ok(content);
+                }
+            },
+        };
+        debug!("Access log of {} was rotated, reading {rotated_log} too", self.config.name);
+
+        Ok(rotated_content + &content)
     }
 
     async fn should_shutdown(&self) -> anyhow::Result<ShouldShutdown> {
         debug!("Checking if site {} should be shut down", self.config.name);
         let now = Utc::now();
 
+        if let Some(min_uptime_end) = self.min_uptime_end() {
+            if min_uptime_end > now {
+                debug!("Site {} is kept up until {min_uptime_end} by min_uptime", self.config.name);
+                return Ok(ShouldShutdown::NotUntil(min_uptime_end));
+            }
+        }
+
+        // Health checks keep going so that the state stays accurate
+        if !self.config.hibernates() {
+            debug!("Site {} is never hibernated", self.config.name);
+            return Ok(ShouldShutdown::NotUntil(now + self.config.recheck_interval()));
+        }
+
         // Read the file and get the last line
-        let content = read_to_string(&self.config.access_log).await.map_err(|e| anyhow!("could not read access log: {e}"))?;
+        let content = self.read_access_log().await?;
         let lines = content.lines();
         let mut rev_lines = lines.rev(); // FIXME: It would be more efficient to use rev_lines but it's not async-compatible
         let mut last_line = 'line: loop {
@@ -142,6 +432,7 @@ impl SiteController {
                         return Ok(ShouldShutdown::NotUntil(now + Duration::from_secs(self.config.keep_alive))); // Not sure keep_alive is the right value to use
                     }
                     
+                    self.last_activity.store(last_changed.timestamp_millis(), Ordering::Relaxed);
                     if (now - last_changed).num_seconds() >= self.config.keep_alive as i64 {
                         return Ok(ShouldShutdown::Now);
                     } else {
@@ -156,51 +447,49 @@ impl SiteController {
                 }
             }
     
+            let ip = potential_last_line.split_whitespace().next().and_then(parse_ip);
+
             if let Some(ip_blacklist) = &self.config.ip_blacklist {
-                for ip_blacklist in ip_blacklist {
-                    if potential_last_line.starts_with(ip_blacklist) {
-                        continue 'line;
-                    }
+                if ip.is_some_and(|ip| ip_blacklist.iter().any(|ip_blacklist| ip_blacklist.contains(&ip))) {
+                    continue 'line;
                 }
             }
     
             if let Some(ip_whitelist) = &self.config.ip_whitelist {
-                let mut found = false;
-                for ip_whitelist in ip_whitelist {
-                    if potential_last_line.starts_with(ip_whitelist) {
-                        found = true;
-                        break;
-                    }
-                }
-                if !found {
+                if !ip.is_some_and(|ip| ip_whitelist.iter().any(|ip_whitelist| ip_whitelist.contains(&ip))) {
                     continue 'line;
                 }
             }
     
-            if let Some(path_blacklist) = &self.config.path_blacklist {
-                let path = potential_last_line.find('"').ok_or(anyhow!("no path container opening quote in last line"))?;
-                let mut potential_path_container = &potential_last_line[path + 1..];
-                let end_path = potential_path_container.find('"').ok_or(anyhow!("no path container closing quote in last line"))?;
-                potential_path_container = &potential_path_container[..end_path];
-                
-                let potential_path = potential_path_container.split(' ').nth(1).ok_or(anyhow!("no path in last line"))?;
-    
-                for path_blacklist in path_blacklist {
-                    if path_blacklist.is_match(potential_path) {
-                        continue 'line;
-                    }
+            // Requests to the paths of a route belong to the route
+            let has_routes = self.config.route_paths.is_some() || !self.config.routes.is_empty();
+            if has_routes && !self.config.handles_path(log_line_path(potential_last_line)?) {
+                continue 'line;
+            }
+
+            if self.config.path_blacklist.is_some() || self.config.default_path_blacklist() {
+                match log_line_path(potential_last_line) {
+                    Ok(potential_path) if self.config.is_path_blacklisted(potential_path) => continue 'line,
+                    Ok(_) => (),
+                    // Only an explicit path_blacklist requires paths in the access log
+                    Err(e) if self.config.path_blacklist.is_some() => return Err(e),
+                    Err(_) => (),
                 }
             }
+
+            if self.config.wake_path_whitelist.is_some() && !self.config.is_wake_path(log_line_path(potential_last_line)?) {
+                continue 'line;
+            }
     
             break potential_last_line;
         };
         
         // Parse the date of the last request
         let last_request = loop {
-            let start_position = last_line.find('[').ok_or(anyhow!("no date in last line"))?;
+            let start_position = last_line.find('[').ok_or(anyhow!("could not find a date in access log line; make sure the access log uses nginx's combined format, with dates like [10/Oct/2000:13:55:36 +0000]"))?;
             last_line = &last_line[start_position + 1..];
     
-            let end_position = last_line.find(']').ok_or(anyhow!("no date in last line"))?;
+            let end_position = last_line.find(']').ok_or(anyhow!("could not find the end of the date in access log line; make sure the access log uses nginx's combined format"))?;
             let date_str = &last_line[..end_position];
             last_line = &last_line[end_position + 1..];
     
@@ -215,6 +504,7 @@ impl SiteController {
         if state != SiteState::Unknown {
             last_action = max(last_action, last_state_change);
         }
+        self.last_activity.store(last_action.timestamp_millis(), Ordering::Relaxed);
         
         // Check if the site should be shut down
         let time_since = now.signed_duration_since(last_action);
@@ -228,34 +518,53 @@ impl SiteController {
         }
     }    
 
+    /// When the site will have been up for `min_uptime`, if it's configured.
+    pub fn min_uptime_end(&self) -> Option<DateTime<Utc>> {
+        let min_uptime = self.config.min_uptime?;
+        let up_since = DateTime::from_timestamp_millis(self.up_since.load(Ordering::Relaxed))?;
+        Some(up_since + Duration::from_secs(min_uptime))
+    }
+
+    /// When a site recorded as starting should have finished starting.
+    /// A start running past this point was interrupted, and the state needs to be reconciled.
+    fn stuck_start_deadline(&self) -> Option<DateTime<Utc>> {
+        let (state, last_changed) = self.get_state_with_last_changed();
+        if state != SiteState::Starting {
+            return None;
+        }
+        Some(last_changed + Duration::from_millis(self.config.start_timeout_ms.0))
+    }
+
     async fn check(&self) -> DateTime<Utc> {
         let now = Utc::now();
 
-        let up = is_healthy(self.config.port).await;
+        if self.stuck_start_deadline().is_some_and(|deadline| deadline < now) {
+            warn!("Site {} has been starting for longer than its start timeout, reconciling its state", self.config.name);
+        }
+
+        let up = is_healthy(self.config).await;
         match up {
             true => {
-                let should_shutdown = match self.should_shutdown().await {
+                let should_shutdown = self.should_shutdown().await;
+                self.record_check_result(should_shutdown.as_ref().map(|_| ()));
+                let should_shutdown = match should_shutdown {
                     Ok(should_shutdown) => should_shutdown,
-                    Err(err) => {
-                        error!("Error while checking site {}: {err}", self.config.name);
+                    Err(_) => {
                         self.set_state(SiteState::Up).await;
-                        return now + Duration::from_secs(self.config.keep_alive);
+                        return now + self.config.recheck_interval();
                     },
                 };
                 match should_shutdown {
                     ShouldShutdown::Now => {
-                        // mark_stopped(&self.config.name).await;
-
-                        info!("Shutting down site {}", self.config.name);
-
-                        self.set_state(SiteState::Down).await;
-                        let r = run_command(&format!("systemctl stop {}", self.config.service_name)).await;
-                        if let Err(e) = r {
-                            error!("Error while shutting down site {}: {e}", self.config.name);
-                            self.set_state(SiteState::Unknown).await;
+                        // nginx only logs requests once they complete, so ongoing downloads or streams don't show up
+                        if let Some(connections) = self.active_connections().await.filter(|connections| *connections > 0) {
+                            debug!("Site {} still has {connections} active connections, not shutting it down", self.config.name);
+                            self.set_state(SiteState::Up).await;
+                            return now + self.config.recheck_interval();
                         }
-                        
-                        now + Duration::from_secs(self.config.keep_alive)
+
+                        info!(site = self.config.name.as_str(), event = "stop"; "Shutting down site {}", self.config.name);
+                        self.stop(now).await
                     },
                     ShouldShutdown::NotUntil(next_check) => {
                         self.set_state(SiteState::Up).await;
@@ -265,53 +574,217 @@ impl SiteController {
             },
             false => {
                 self.set_state(SiteState::Down).await;
-                now + Duration::from_secs(self.config.keep_alive)
+                now + self.config.recheck_interval()
+            }
+        }
+    }
+
+    /// Number of connections the service is serving, according to `active_connections_command`.
+    /// Errors are logged and treated as unknown, so that a broken command doesn't keep the site up forever.
+    async fn active_connections(&self) -> Option<u64> {
+        let command = self.config.active_connections_command.as_deref()?;
+        let output = match run_command_output(command).await {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("Could not count active connections of site {}: {e}", self.config.name);
+                return None;
+            }
+        };
+        match output.trim().parse() {
+            Ok(connections) => Some(connections),
+            Err(_) => {
+                warn!("active_connections_command of site {} printed {:?} instead of a number", self.config.name, output.trim());
+                None
+            }
+        }
+    }
+
+    /// Stops the service and waits for it to stop responding. Returns when the site should be checked again.
+    async fn stop(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.last_cycle_requests.store(self.cycle_requests.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+        self.set_state(SiteState::Stopping).await;
+        let r = run_program(&self.config.systemctl_argv("stop")).await;
+        if let Err(e) = r {
+            error!("Error while shutting down site {}: {e}", self.config.name);
+            self.set_state(SiteState::Unknown).await;
+            return now + self.config.recheck_interval();
+        }
+
+        // Wait until the site stops responding
+        let stop = Instant::now();
+        let mut failures = 0;
+        let state = loop {
+            match is_healthy(self.config).await {
+                true => failures = 0,
+                false => failures += 1,
+            }
+            if failures >= self.config.health_check_success_threshold() {
+                break SiteState::Down;
+            }
+            if stop.elapsed() > Duration::from_millis(self.config.stop_timeout_ms.0) {
+                error!("Site {} did not stop in time", self.config.name);
+                break SiteState::Unknown;
+            }
+            sleep(Duration::from_millis(self.config.start_check_interval_ms.0)).await;
+        };
+        self.set_state(state).await;
+
+        now + self.config.recheck_interval()
+    }
+
+    pub fn failed_starts(&self) -> u32 {
+        self.failed_starts.load(Ordering::Relaxed)
+    }
+
+    pub fn counters(&self) -> SiteCounters {
+        *self.counters.lock().expect("counters lock poisoned")
+    }
+
+    /// Returns when the next start will be allowed, if starts are currently being delayed due to repeated failures.
+    pub fn start_backoff_until(&self) -> Option<DateTime<Utc>> {
+        let failed_starts = self.failed_starts();
+        if failed_starts < START_FAILURES_BEFORE_BACKOFF {
+            return None;
+        }
+
+        let exponent = min(failed_starts - START_FAILURES_BEFORE_BACKOFF, 16);
+        let backoff = min(START_BACKOFF_BASE * 2u32.pow(exponent), START_BACKOFF_MAX);
+        let last_failed_start = DateTime::from_timestamp_millis(self.last_failed_start.load(Ordering::Relaxed))?;
+        let until = last_failed_start + backoff;
+
+        (until > Utc::now()).then_some(until)
+    }
+
+    fn record_start_result(&self, state: SiteState) {
+        if state.is_up() {
+            self.failed_starts.store(0, Ordering::Relaxed);
+        } else {
+            self.last_failed_start.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+            let failed_starts = self.failed_starts.fetch_add(1, Ordering::Relaxed) + 1;
+            if failed_starts >= START_FAILURES_BEFORE_BACKOFF {
+                warn!("Site {} failed to start {failed_starts} times in a row, delaying further starts", self.config.name);
             }
         }
     }
 
-    async fn start(&self, started_sender: &BroadSender<()>) {    
+    /// Returns when the site should be checked again, if that should happen earlier than planned.
+    async fn start(&self, source: TriggerSource, started_sender: &BroadSender<SiteState>) -> Option<DateTime<Utc>> {
+        // The site might have been started externally in the meantime
+        if is_healthy(self.config).await {
+            debug!("Site {} is already healthy, not starting it", self.config.name);
+            self.record_start_result(SiteState::Up);
+            self.set_state(SiteState::Up).await;
+            let _ = started_sender.send(SiteState::Up);
+            return None;
+        }
+
+        if let Some(until) = self.start_backoff_until() {
+            debug!("Not starting site {} before {until} due to previous failures", self.config.name);
+            let _ = started_sender.send(self.get_state());
+            return None;
+        }
+
         // Try to atomically update state to Starting, but only if not already Up or Starting
-        let can_start = DATABASE
-            .try_update_state(&self.config.name, SiteState::Starting, &[SiteState::Up, SiteState::Starting])
-            .expect("could not check/update site state in database");
+        let can_start = match DATABASE.try_update_state(&self.config.name, SiteState::Starting, &[SiteState::Up, SiteState::Starting], Some(source)) {
+            Ok(can_start) => can_start,
+            Err(e) => {
+                error!("Couldn't record that {} is starting, not starting it: {e}", self.config.name);
+                let _ = started_sender.send(self.get_state());
+                return None;
+            }
+        };
 
         if !can_start {
             trace!("Site {} is already up or starting", self.config.name);
-            return;
+            let _ = started_sender.send(self.get_state());
+            return None;
         }
 
-        info!("Starting service {}", self.config.name);
-        let r = run_command(&format!("systemctl start {}", self.config.service_name)).await;
+        info!(site = self.config.name.as_str(), event = "start", trigger_source = source.as_str(); "Starting service {} (triggered by {})", self.config.name, source.as_str());
+        let r = run_program(&self.config.systemctl_argv("start")).await;
         if let Err(e) = r {
-            error!("Error while starting site {}: {e}", self.config.name);
+            error!("Start command of site {} failed: {e}", self.config.name);
+            self.record_start_result(SiteState::Unknown);
             self.set_state(SiteState::Unknown).await;
-            return;
+            let _ = started_sender.send(SiteState::Unknown);
+            return None;
         }
 
         // Wait until the site is healthy
         let start = Instant::now();
+        let mut successes = 0;
         let state = loop {
             if start.elapsed() > Duration::from_millis(self.config.start_timeout_ms.0) {
-                error!("Site {} did not start in time", self.config.name);
+                error!(
+                    "Site {} was started but did not become ready within {}ms, checking its state again soon",
+                    self.config.name, self.config.start_timeout_ms.0
+                );
                 break SiteState::Unknown;
             }
 
-            let is_up = is_healthy(self.config.port).await;
-            if is_up {
+            // A lingering socket of the previous instance could answer once, so require consecutive successes
+            match is_ready(self.config).await {
+                true => successes += 1,
+                false => successes = 0,
+            }
+            if successes >= self.config.health_check_success_threshold() {
                 break SiteState::Up;
             }
             sleep(Duration::from_millis(self.config.start_check_interval_ms.0)).await;
         };
+        self.record_start_result(state);
         self.set_state(state).await;
-        let _ = started_sender.send(());
+        if state.is_up() {
+            self.record_wake_waiters();
+        }
+        let _ = started_sender.send(state);
+
+        // The site might still come up after the timeout, so don't leave it unknown until the next request
+        (state == SiteState::Unknown).then(|| Utc::now() + Duration::from_millis(self.config.start_check_interval_ms.0))
+    }
+
+    /// Starts a disabled site that was left hibernated, and points nginx back at it once it is up.
+    /// nginx keeps the hibernator config if the site doesn't come up, so that visitors still get the landing page.
+    async fn start_disabled(&self) {
+        info!("Starting disabled site {} as it is down", self.config.name);
+        if let Err(e) = run_program(&self.config.systemctl_argv("start")).await {
+            error!("Start command of disabled site {} failed, keeping the hibernator config: {e}", self.config.name);
+            return;
+        }
+
+        let start = Instant::now();
+        while !is_healthy(self.config).await {
+            if start.elapsed() > Duration::from_millis(self.config.start_timeout_ms.0) {
+                error!(
+                    "Disabled site {} did not become ready within {}ms, keeping the hibernator config",
+                    self.config.name, self.config.start_timeout_ms.0
+                );
+                return;
+            }
+            sleep(Duration::from_millis(self.config.start_check_interval_ms.0)).await;
+        }
+
+        self.on_up().await;
     }
 
-    pub async fn handle(&self, mut start_receiver: Receiver<()>, started_sender: BroadSender<()>) {
+    pub async fn handle(&self, mut start_receiver: Receiver<TriggerSource>, started_sender: BroadSender<SiteState>) {
+        if !self.config.enabled() {
+            info!("Site {} is disabled, it won't be managed", self.config.name);
+            // A site disabled while hibernated would stay down, as nothing would ever start it
+            if !is_healthy(self.config).await {
+                self.start_disabled().await;
+            }
+            shutdown_requested().await;
+            return;
+        }
+
         let mut next_check: DateTime<Utc> = Utc::now();
     
         loop {
             let now = Utc::now();
+            if let Some(deadline) = self.stuck_start_deadline() {
+                next_check = min(next_check, deadline);
+            }
             let to_wait = next_check.signed_duration_since(now);
             debug!("Waiting for {to_wait} seconds before checking site {}", self.config.name);
             
@@ -319,31 +792,102 @@ impl SiteController {
             let recv_task = start_receiver.recv();
     
             tokio::select! {
-                _ = sleep_task => next_check = self.check().await,
-                _ = recv_task => self.start(&started_sender).await,
+                _ = sleep_task => next_check = with_jitter(self.check().await),
+                _ = self.stop_requested.notified() => {
+                    let now = Utc::now();
+                    if self.get_state().is_up() && self.min_uptime_end().is_none_or(|min_uptime_end| min_uptime_end <= now) {
+                        info!(site = self.config.name.as_str(), event = "stop"; "Shutting down site {} on request", self.config.name);
+                        next_check = with_jitter(self.stop(now).await);
+                    }
+                },
+                Some(source) = recv_task => {
+                    if let Some(recheck) = self.start(source, &started_sender).await {
+                        next_check = min(next_check, recheck);
+                    }
+                },
+                _ = shutdown_requested() => return,
             }
         }
     }
 }
 
-pub static mut SITE_CONTROLLERS: &[SiteController] = &[];
+/// Request coalescing statistics of a site, kept since the hibernator started
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct WakeStats {
+    /// Requests currently waiting for the site to start
+    pub current_waiters: u32,
+    /// Requests that were waiting when the site last became ready
+    pub last_wake_waiters: u32,
+    /// Most requests ever waiting when the site became ready
+    pub max_wake_waiters: u32,
+    /// Requests proxied since the site was last woken
+    pub current_cycle_requests: u64,
+    /// Requests proxied between the previous wake and hibernation
+    pub last_cycle_requests: u64,
+}
 
-pub fn get_controller(host: &String) -> Option<&'static SiteController> {
-    // SAFETY:
-    // Accessing the static mutable is safe because it's only accessed in a read-only way during
-    // the server execution. The value is only mutated once, before the server starts.
-    #[allow(static_mut_refs)]
-    unsafe {
-        SITE_CONTROLLERS.iter().find(|controller| controller.config.hosts.contains(host))
+/// Counts a waiting request for as long as it's alive, so that requests given up on by a timeout are uncounted
+struct WaiterGuard<'a>(&'a AtomicU32);
+
+impl<'a> WaiterGuard<'a> {
+    fn new(waiters: &'a AtomicU32) -> Self {
+        waiters.fetch_add(1, Ordering::Relaxed);
+        WaiterGuard(waiters)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Extracts the request path from an access log line in nginx's combined format
+fn log_line_path(line: &str) -> anyhow::Result<&str> {
+    let path = line.find('"').ok_or(anyhow!("no path container opening quote in last line"))?;
+    let mut path_container = &line[path + 1..];
+    let end_path = path_container.find('"').ok_or(anyhow!("no path container closing quote in last line"))?;
+    path_container = &path_container[..end_path];
+
+    path_container.split(' ').nth(1).ok_or(anyhow!("no path in last line"))
+}
+
+/// Randomly moves a check time by up to `check_jitter_ms` in either direction.
+fn with_jitter(time: DateTime<Utc>) -> DateTime<Utc> {
+    let jitter_ms = current_config().top_level.check_jitter_ms();
+    if jitter_ms == 0 {
+        return time;
+    }
+    let offset = (random_u64() % (2 * jitter_ms + 1)) as i64 - jitter_ms as i64;
+    time + chrono::Duration::milliseconds(offset)
+}
+
+// Controllers are leaked so that references to them stay valid after a config reload replaces them
+static SITE_CONTROLLERS: RwLock<&'static [&'static SiteController]> = RwLock::new(&[]);
+
+pub fn site_controllers() -> &'static [&'static SiteController] {
+    *SITE_CONTROLLERS.read().expect("site controllers lock poisoned")
+}
+
+pub fn set_site_controllers(controllers: Vec<&'static SiteController>) {
+    *SITE_CONTROLLERS.write().expect("site controllers lock poisoned") = controllers.leak();
+}
+
+pub fn get_controller(host: &String, path: &str) -> Option<&'static SiteController> {
+    site_controllers().iter().find(|controller| controller.config.hosts.contains(host) && controller.config.handles_path(path)).copied()
+}
+
+pub fn get_controller_by_name(name: &str) -> Option<&'static SiteController> {
+    site_controllers().iter().find(|controller| controller.config.name == name).copied()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum SiteState {
     Unknown,
     Down,
     Up,
-    Starting
+    Starting,
+    Stopping,
     // TODO: Allow tracking whether we started it or it was started externally
 }
 
@@ -351,6 +895,34 @@ impl SiteState {
     pub fn is_up(&self) -> bool {
         matches!(self, SiteState::Up)
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SiteState::Unknown => "unknown",
+            SiteState::Down => "down",
+            SiteState::Up => "up",
+            SiteState::Starting => "starting",
+            SiteState::Stopping => "stopping",
+        }
+    }
+}
+
+/// What caused a site to be started
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerSource {
+    /// A request reached the hibernator
+    Request,
+    /// The site was woken through the API
+    Api,
+}
+
+impl TriggerSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TriggerSource::Request => "request",
+            TriggerSource::Api => "api",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -359,3 +931,184 @@ enum ShouldShutdown {
     NotUntil(DateTime<Utc>),
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::set_current_config, test_utils::*, Config};
+    use super::*;
+
+    /// A controller for the first site of the config, without its task so that tests drive it
+    async fn test_controller(config: &'static Config) -> (&'static SiteController, BroadSender<SiteState>) {
+        set_current_config(config);
+        let (controller, _, started_sender) = SiteController::new(&config.sites[0]).await;
+        (Box::leak(Box::new(controller)), started_sender)
+    }
+
+    #[tokio::test]
+    async fn start_skips_healthy_sites() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let (upstream_port, _) = upstream(ok_response("up")).await;
+        let config = env.load("", vec![env.site("already-up", upstream_port, "")]);
+        let (controller, started_sender) = test_controller(config).await;
+        let mut started_receiver = started_sender.subscribe();
+
+        controller.start(TriggerSource::Request, &started_sender).await;
+        assert_eq!(started_receiver.try_recv().unwrap(), SiteState::Up);
+        assert_eq!(controller.get_state(), SiteState::Up);
+        assert!(!env.commands().iter().any(|command| command.starts_with("systemctl")), "{:?}", env.commands());
+    }
+
+    #[tokio::test]
+    async fn slow_stops_are_reported_as_stopping() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = env.load("", vec![env.site("slow-stop", listener.local_addr().unwrap().port(), "")]);
+        let (controller, _) = test_controller(config).await;
+        DATABASE.update_state("slow-stop", SiteState::Up).unwrap();
+
+        tokio::join!(controller.stop(Utc::now()), async move {
+            // The service keeps answering for a while after systemctl returns
+            while controller.get_state() != SiteState::Stopping {
+                sleep(Duration::from_millis(10)).await;
+            }
+            sleep(Duration::from_millis(200)).await;
+            assert_eq!(controller.get_state(), SiteState::Stopping);
+            drop(listener);
+        });
+
+        assert!(env.commands().contains(&String::from("systemctl stop slow-stop.service")), "{:?}", env.commands());
+        let history = DATABASE.get_state_history_since("slow-stop", DateTime::from_timestamp_nanos(0)).unwrap();
+        let states: Vec<SiteState> = history.into_iter().map(|(_, state)| state).collect();
+        assert_eq!(states, [SiteState::Unknown, SiteState::Up, SiteState::Stopping, SiteState::Down]);
+    }
+
+    #[tokio::test]
+    async fn nginx_is_left_alone_when_already_switched() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let config = env.load("", vec![env.site("switch", free_port(), "")]);
+        let (controller, _) = test_controller(config).await;
+
+        controller.on_down().await;
+        assert_eq!(env.commands(), Vec::<String>::new());
+        assert_eq!(controller.nginx_error(), None);
+
+        controller.on_up().await;
+        assert_eq!(env.commands(), ["nginx -t", "nginx -t", "nginx -s reload"]);
+        assert_eq!(std::fs::read_link(env.path("enabled-switch")).unwrap().to_str(), Some(env.path("switch.conf").as_str()));
+    }
+
+    #[tokio::test]
+    async fn only_whitelisted_paths_count_as_activity() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let config = env.load("", vec![env.site("whitelisted-log", free_port(), "wake_path_whitelist = [\"/app/*\"]")]);
+        let (controller, _) = test_controller(config).await;
+
+        let log_line = |at: DateTime<Utc>, path: &str| format!("127.0.0.1 - - [{}] \"GET {path} HTTP/1.1\" 200 2 \"-\" \"curl\"\n", at.format("%d/%b/%Y:%H:%M:%S %z"));
+        let two_hours_ago = Utc::now() - Duration::from_secs(7200);
+        std::fs::write(env.path("whitelisted-log.log"), log_line(two_hours_ago, "/app/page") + &log_line(Utc::now(), "/other")).unwrap();
+        assert!(matches!(controller.should_shutdown().await.unwrap(), ShouldShutdown::Now));
+
+        std::fs::write(env.path("whitelisted-log.log"), log_line(two_hours_ago, "/other") + &log_line(Utc::now(), "/app/page")).unwrap();
+        assert!(matches!(controller.should_shutdown().await.unwrap(), ShouldShutdown::NotUntil(_)));
+    }
+
+    #[tokio::test]
+    async fn stale_starts_are_reconciled_by_health() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let port = free_port();
+        let sites = vec![env.site("stale-down", port, "start_timeout_ms = 1"), env.site("stale-up", port, "start_timeout_ms = 1")];
+        let config = env.load("", sites);
+
+        set_current_config(config);
+        let (down, _, _) = SiteController::new(&config.sites[0]).await;
+        DATABASE.update_state("stale-down", SiteState::Starting).unwrap();
+        sleep(Duration::from_millis(10)).await;
+        down.check().await;
+        assert_eq!(down.get_state(), SiteState::Down);
+
+        let _listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let (up, _, _) = SiteController::new(&config.sites[1]).await;
+        DATABASE.update_state("stale-up", SiteState::Starting).unwrap();
+        sleep(Duration::from_millis(10)).await;
+        up.check().await;
+        assert_eq!(up.get_state(), SiteState::Up);
+        assert!(logs().iter().any(|line| line == "WARN Site stale-up has been starting for longer than its start timeout, reconciling its state"));
+    }
+
+    #[tokio::test]
+    async fn compressed_rotated_logs_are_read() {
+        use std::io::Write;
+
+        let env = TestEnv::new();
+        let config = env.load("", vec![env.site("rotated", free_port(), "")]);
+        let (controller, _, _) = SiteController::new(&config.sites[0]).await;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"rotated line\n").unwrap();
+        let rotated_log = format!("{}{}.gz", env.path("rotated.log"), config.sites[0].log_rotation_suffix());
+        std::fs::write(rotated_log, encoder.finish().unwrap()).unwrap();
+        std::fs::write(env.path("rotated.log"), "").unwrap();
+
+        assert_eq!(controller.read_access_log().await.unwrap(), "rotated line\n");
+    }
+
+    #[tokio::test]
+    async fn starts_recorded_in_the_future_have_no_progress() {
+        let env = TestEnv::new();
+        let config = env.load("", vec![env.site("skewed", free_port(), "")]);
+        let an_hour_ago = Utc::now() - Duration::from_secs(3600);
+        DATABASE.put_state_at("skewed", SiteState::Starting, an_hour_ago).unwrap();
+        DATABASE.put_state_at("skewed", SiteState::Up, an_hour_ago + Duration::from_secs(10)).unwrap();
+        let (controller, _, _) = SiteController::new(&config.sites[0]).await;
+        DATABASE.put_state_at("skewed", SiteState::Starting, Utc::now() + Duration::from_secs(60)).unwrap();
+
+        assert_eq!(controller.get_progress().await, Some((Duration::ZERO, Duration::from_secs(10))));
+    }
+
+    #[tokio::test]
+    async fn hibernated_sites_are_started_when_disabled() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let started = env.path("started");
+        let systemctl = env.script("starting-systemctl", &format!("echo \"systemctl $@\" >> {}\ntouch {started}", env.path("commands")));
+        let site = env.site("disabled", free_port(), &format!("enabled = false\nsystemctl_prefix = \"{systemctl}\"\nhealth_check_command = \"test -f {started}\""));
+        let config = env.load("", vec![site]);
+        set_current_config(config);
+        SiteController::spawn(&config.sites[0]).await;
+
+        env.wait_for_command("nginx -s reload").await;
+        assert_eq!(env.commands(), ["systemctl start disabled.service", "nginx -t", "nginx -t", "nginx -s reload"]);
+        assert_eq!(std::fs::read_link(env.path("enabled-disabled")).unwrap().to_str(), Some(env.path("disabled.conf").as_str()));
+    }
+
+    #[tokio::test]
+    async fn disabled_sites_that_dont_start_keep_the_hibernator_config() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let failing_systemctl = env.script("failing-systemctl", "exit 1");
+        let sites = vec![
+            env.site("disabled-failing", free_port(), &format!("enabled = false\nsystemctl_prefix = \"{failing_systemctl}\"")),
+            env.site("disabled-dead", free_port(), "enabled = false\nstart_timeout_ms = 100"),
+        ];
+        let config = env.load("", sites);
+        set_current_config(config);
+        SiteController::spawn(&config.sites[0]).await;
+        SiteController::spawn(&config.sites[1]).await;
+
+        let given_up = |prefix: &str| logs().iter().any(|line| line.starts_with(prefix));
+        tokio::time::timeout(TEST_TIMEOUT, async {
+            while !given_up("ERROR Disabled site disabled-dead did not become ready") || !given_up("ERROR Start command of disabled site disabled-failing failed") {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("the sites were never given up on");
+        assert!(!env.commands().iter().any(|command| command.starts_with("nginx")), "{:?}", env.commands());
+        for name in ["disabled-failing", "disabled-dead"] {
+            assert_eq!(std::fs::read_link(env.path(&format!("enabled-{name}"))).unwrap().to_str(), Some(env.path("hibernator.conf").as_str()));
+        }
+    }
+}
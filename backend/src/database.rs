@@ -1,41 +1,154 @@
 use anyhow::{Result as AnyResult, anyhow};
 use chrono::{DateTime, Utc};
 use heed::{
-    Database as HeedDatabase, EnvOpenOptions, byteorder::BigEndian, types::{Str, U64}
+    Database as HeedDatabase, EnvOpenOptions, RoTxn, RwTxn, byteorder::BigEndian, types::{SerdeJson, Str, U64}
 };
+use log::*;
 use serde::{Deserialize, Serialize};
 use std::{sync::LazyLock, time::Duration};
-use crate::{config::Config, controller::SiteState, server::ConnectionMetadata, bincoded::Bincoded};
+use tokio::time::sleep;
+use crate::{config::current_config, util::now, controller::{SiteState, TriggerSource}, server::{ConnectionMetadata, ConnectionResult}, bincoded::Bincoded};
 
 pub static DATABASE: LazyLock<Database> = LazyLock::new(Database::open);
 
-const LATEST_DB_VERSION: u64 = 0;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-#[derive(Serialize, Deserialize)]
+const LATEST_DB_VERSION: u64 = 1;
+
+/// A period during which a site stayed in the same state: (start, end, state)
+pub type StateRange = (DateTime<Utc>, DateTime<Utc>, SiteState);
+
+#[derive(Serialize, Deserialize, Clone)]
 struct StateChangeKey {
     pub service: String,
     #[serde(with = "chrono::serde::ts_nanoseconds")]
     pub timestamp: DateTime<Utc>,
 }
 
+/// Lifetime counters of a site, maintained as its state changes
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct SiteCounters {
+    /// Number of times the site went up after being started
+    pub total_wakes: u64,
+    /// Number of times the site went down after being up
+    pub total_hibernations: u64,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub last_wake: Option<DateTime<Utc>>,
+}
+
+impl SiteCounters {
+    /// Updates the counters for a state change
+    pub fn record(&mut self, old_state: SiteState, new_state: SiteState, at: DateTime<Utc>) {
+        match (old_state, new_state) {
+            (SiteState::Starting, SiteState::Up) => {
+                self.total_wakes += 1;
+                self.last_wake = Some(at);
+            }
+            (SiteState::Up, SiteState::Down | SiteState::Stopping) => self.total_hibernations += 1,
+            _ => (),
+        }
+    }
+}
+
 pub struct Database {
     env: heed::Env,
-    connections: HeedDatabase<U64<BigEndian>, Bincoded<Vec<ConnectionMetadata>>>,
+    // Stored as JSON so that new optional fields can be added without migrating the database
+    connections: HeedDatabase<U64<BigEndian>, SerdeJson<Vec<ConnectionMetadata>>>,
     states: HeedDatabase<Bincoded<StateChangeKey>, Bincoded<SiteState>>,
+    /// What triggered starts, keyed like the corresponding `Starting` state change
+    triggers: HeedDatabase<Bincoded<StateChangeKey>, Bincoded<TriggerSource>>,
+    counters: HeedDatabase<Str, SerdeJson<SiteCounters>>,
+}
+
+/// Connection metadata as stored in version 0 of the database
+#[derive(Deserialize)]
+struct ConnectionMetadataV0 {
+    request: Vec<String>,
+    result: ConnectionResult,
+    service: Option<String>,
+    is_browser: bool,
+    real_ip: Option<String>,
+    method: String,
+    url: String,
+}
+
+/// Version 1 stores connection metadata as JSON instead of bincode
+fn migrate_v0_to_v1(env: &heed::Env, wtxn: &mut RwTxn) -> AnyResult<()> {
+    let Some(old_connections) = env.open_database::<U64<BigEndian>, Bincoded<Vec<ConnectionMetadataV0>>>(wtxn, Some("connections"))? else {
+        return Ok(());
+    };
+
+    let mut entries = Vec::new();
+    for entry in old_connections.iter(wtxn)? {
+        let (at, metadatas) = entry?;
+        let metadatas: Vec<ConnectionMetadata> = metadatas.into_iter().map(|old| ConnectionMetadata {
+            request: old.request,
+            result: old.result,
+            service: old.service,
+            is_browser: old.is_browser,
+            real_ip: old.real_ip,
+            method: old.method,
+            url: old.url,
+            status: None,
+            cold: false,
+            wake_wait_ms: None,
+            upstream_ms: None,
+            country: None,
+        }).collect();
+        entries.push((at, metadatas));
+    }
+    old_connections.clear(wtxn)?;
+
+    let new_connections = old_connections.remap_data_type::<SerdeJson<Vec<ConnectionMetadata>>>();
+    for (at, metadatas) in entries {
+        new_connections.put(wtxn, &at, &metadatas)?;
+    }
+
+    Ok(())
+}
+
+/// Explains how to make room when a write fails because the database is full
+fn write_error(e: heed::Error) -> anyhow::Error {
+    match e {
+        heed::Error::Mdb(heed::MdbError::MapFull) => anyhow!("database is full, consider increasing database_map_size or setting history_retention"),
+        e => e.into(),
+    }
+}
+
+/// The value at `percentile` (0 to 100) of already sorted values, rounding down to the closest value
+pub fn percentile<T: Copy>(sorted: &[T], percentile: usize) -> Option<T> {
+    let idx = (sorted.len() * percentile / 100).min(sorted.len().checked_sub(1)?);
+    Some(sorted[idx])
 }
 
 impl Database {
+    #[cfg(not(test))]
+    fn open() -> Self {
+        use crate::config::{config_source, parse_config_file, TopLevelConfig};
+
+        let config_data = config_source().read().expect("could not read config");
+        // Sites are only parsed along with the rest of the config, with their defaults applied
+        let top_level: TopLevelConfig = parse_config_file(&config_data, None).expect("could not parse config file");
+
+        Database::open_at(top_level.database_path(), top_level.database_map_size())
+    }
+
+    /// Tests have no config file, so they share a database in a temporary directory
+    #[cfg(test)]
     fn open() -> Self {
-        let config_path = std::env::args().nth(1).unwrap_or(String::from("config.toml"));
-        let config_data = std::fs::read_to_string(config_path).expect("could not read config file");
-        let config: Config = toml::from_str(&config_data).expect("could not parse config file");
-        let path = config.top_level.database_path();
+        use crate::config::TopLevelConfig;
+
+        let path = tempfile::tempdir().expect("couldn't create database directory").keep();
+        let top_level: TopLevelConfig = toml::from_str("").expect("could not build default config");
+        Database::open_at(path.to_str().expect("temporary directory isn't UTF-8"), top_level.database_map_size())
+    }
 
+    fn open_at(path: &str, map_size: usize) -> Self {
         std::fs::create_dir_all(path).expect("couldn't create database directory");
 
         let env = unsafe {
             EnvOpenOptions::new()
-                .map_size(10 * 4096 * 4096) // 160MiB
+                .map_size(map_size)
                 .max_dbs(16)
                 .open(path)
                 .expect("couldn't open database")
@@ -55,6 +168,7 @@ impl Database {
 
         match version {
             Some(LATEST_DB_VERSION) | None => {}
+            Some(0) => migrate_v0_to_v1(&env, &mut wtxn).expect("couldn't upgrade database from version 0"),
             Some(unsupported_version) => {
                 panic!("cannot upgrade from unsupported database version {unsupported_version}")
             }
@@ -76,9 +190,17 @@ impl Database {
             .create_database(&mut wtxn, Some("states"))
             .expect("couldn't create tokens database");
 
+        let triggers = env
+            .create_database(&mut wtxn, Some("triggers"))
+            .expect("couldn't create triggers database");
+
+        let counters = env
+            .create_database(&mut wtxn, Some("counters"))
+            .expect("couldn't create counters database");
+
         wtxn.commit().expect("couldn't commit transaction");
 
-        Database { env, connections, states }
+        Database { env, connections, states, triggers, counters }
     }
 
     pub fn put_connection_metadata(&self, at: u64, metadata: ConnectionMetadata) -> AnyResult<()> {
@@ -86,10 +208,8 @@ impl Database {
 
         let mut list = self.connections.get(&wtxn, &at)?.unwrap_or_default();
         list.push(metadata);
-        self.connections.put(&mut wtxn, &at, &list)?;
+        self.connections.put(&mut wtxn, &at, &list).and_then(|()| wtxn.commit()).map_err(write_error)?;
 
-        wtxn.commit()?;
-        
         Ok(())
     }
 
@@ -144,7 +264,24 @@ impl Database {
         Ok(results)
     }
 
-    pub fn get_state_history(&self, service: &str, before: Option<DateTime<Utc>>, after: Option<DateTime<Utc>>, min_results: usize) -> AnyResult<Vec<(DateTime<Utc>, DateTime<Utc>, SiteState)>> {
+    /// Returns all requests to a service since the given timestamp (in seconds), oldest first.
+    pub fn get_service_connections_since(&self, service: &str, since: u64) -> AnyResult<Vec<(u64, ConnectionMetadata)>> {
+        let rtxn = self.env.read_txn()?;
+
+        let mut results = Vec::new();
+        for entry in self.connections.range(&rtxn, &(since..))? {
+            let (at, metadatas) = entry?;
+            for metadata in metadatas {
+                if metadata.service.as_deref() == Some(service) {
+                    results.push((at, metadata));
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    pub fn get_state_history(&self, service: &str, before: Option<DateTime<Utc>>, after: Option<DateTime<Utc>>, min_results: usize) -> AnyResult<Vec<StateRange>> {
         let rtxn = self.env.read_txn()?;
 
         let mut raw_results = Vec::new();
@@ -288,7 +425,8 @@ impl Database {
         Ok(results)
     }
 
-    pub fn get_start_duration_estimate(&self, name: &str, percentile: usize) -> AnyResult<Duration> {
+    /// Returns how long the most recent starts took, newest first, up to `max_samples` of them.
+    pub fn get_start_durations(&self, name: &str, max_samples: usize) -> AnyResult<Vec<Duration>> {
         let rtxn = self.env.read_txn()?;
 
         let min = StateChangeKey {
@@ -304,6 +442,9 @@ impl Database {
         let mut values = Vec::new();
         let mut last_started_time = None;
         while let Some((key, state)) = iter.next().transpose()? {
+            if values.len() >= max_samples {
+                break;
+            }
             match state {
                 SiteState::Up => {
                     last_started_time = Some(key.timestamp);
@@ -322,35 +463,49 @@ impl Database {
             }
         }
 
+        Ok(values)
+    }
+
+    pub fn get_start_duration_estimate(&self, name: &str, max_samples: usize, percentile: usize) -> AnyResult<Duration> {
+        let mut values = self.get_start_durations(name, max_samples)?;
+
         if values.is_empty() {
             return Err(anyhow!("No durations stored"));
         }
 
-        let idx = (values.len() * percentile) / 100;
-
-        Ok(values[idx])
+        values.sort();
+        self::percentile(&values, percentile).ok_or(anyhow!("No durations stored"))
     }
 
+    /// Records a new state, unless it's already the latest one.
     pub fn update_state(&self, name: &str, state: SiteState) -> AnyResult<()> {
         let mut wtxn = self.env.write_txn()?;
 
+        if self.last_state_in(&wtxn, name)? == Some(state) {
+            return Ok(());
+        }
+
         let key = StateChangeKey {
             service: name.to_string(),
             timestamp: Utc::now(),
         };
 
-        self.states.put(&mut wtxn, &key, &state)?;
-        wtxn.commit()?;
+        self.states.put(&mut wtxn, &key, &state).and_then(|()| wtxn.commit()).map_err(write_error)?;
 
         Ok(())
     }
 
-    /// Try to update state only if it's not already in the specified state or states.
-    /// Returns true if the state was updated, false if it was already in one of the excluded states.
-    pub fn try_update_state(&self, name: &str, new_state: SiteState, exclude_states: &[SiteState]) -> AnyResult<bool> {
+    /// Records a state change at a given time, so that tests can set up a history
+    #[cfg(test)]
+    pub fn put_state_at(&self, name: &str, state: SiteState, timestamp: DateTime<Utc>) -> AnyResult<()> {
         let mut wtxn = self.env.write_txn()?;
+        let key = StateChangeKey { service: name.to_string(), timestamp };
+        self.states.put(&mut wtxn, &key, &state).and_then(|()| wtxn.commit()).map_err(write_error)?;
+        Ok(())
+    }
 
-        // Check current state within the transaction
+    /// Returns the latest state recorded for a service, as seen by the given transaction.
+    fn last_state_in(&self, txn: &RoTxn, name: &str) -> AnyResult<Option<SiteState>> {
         let min = StateChangeKey {
             service: name.to_string(),
             timestamp: DateTime::from_timestamp_nanos(0),
@@ -359,9 +514,19 @@ impl Database {
             service: name.to_string(),
             timestamp: DateTime::from_timestamp_nanos(i64::MAX),
         };
-        let mut iter = self.states.rev_range(&wtxn, &(min..=max))?;
+        let mut iter = self.states.rev_range(txn, &(min..=max))?;
 
-        if let Some((_, current_state)) = iter.next().transpose()? {
+        Ok(iter.next().transpose()?.map(|(_, state)| state))
+    }
+
+    /// Try to update state only if it's not already in the specified state or states.
+    /// Returns true if the state was updated, false if it was already in one of the excluded states.
+    /// The trigger source, if any, is recorded along with the state change.
+    pub fn try_update_state(&self, name: &str, new_state: SiteState, exclude_states: &[SiteState], trigger_source: Option<TriggerSource>) -> AnyResult<bool> {
+        let mut wtxn = self.env.write_txn()?;
+
+        // Check current state within the transaction
+        if let Some(current_state) = self.last_state_in(&wtxn, name)? {
             // Check if current state is in the exclude list
             if exclude_states.contains(&current_state) {
                 return Ok(false);
@@ -369,18 +534,56 @@ impl Database {
         }
 
         // State is not excluded, proceed with update
-        drop(iter);
         let key = StateChangeKey {
             service: name.to_string(),
             timestamp: Utc::now(),
         };
 
-        self.states.put(&mut wtxn, &key, &new_state)?;
-        wtxn.commit()?;
+        self.states.put(&mut wtxn, &key, &new_state).map_err(write_error)?;
+        if let Some(trigger_source) = trigger_source {
+            self.triggers.put(&mut wtxn, &key, &trigger_source).map_err(write_error)?;
+        }
+        wtxn.commit().map_err(write_error)?;
 
         Ok(true)
     }
 
+    /// Returns the counters of a site.
+    /// They are computed from the state history the first time, and maintained with `put_site_counters` afterwards.
+    pub fn get_site_counters(&self, service: &str) -> AnyResult<SiteCounters> {
+        let rtxn = self.env.read_txn()?;
+        if let Some(counters) = self.counters.get(&rtxn, service)? {
+            return Ok(counters);
+        }
+        drop(rtxn);
+
+        let mut counters = SiteCounters::default();
+        let history = self.get_state_history_since(service, DateTime::from_timestamp_nanos(0))?;
+        for window in history.windows(2) {
+            let ((_, old_state), (at, new_state)) = (window[0], window[1]);
+            counters.record(old_state, new_state, at);
+        }
+
+        Ok(counters)
+    }
+
+    pub fn put_site_counters(&self, service: &str, counters: &SiteCounters) -> AnyResult<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.counters.put(&mut wtxn, service, counters).and_then(|()| wtxn.commit()).map_err(write_error)?;
+
+        Ok(())
+    }
+
+    /// Returns what triggered the state change of a service at the given time, if it was recorded.
+    pub fn get_trigger_source(&self, service: &str, timestamp: DateTime<Utc>) -> AnyResult<Option<TriggerSource>> {
+        let rtxn = self.env.read_txn()?;
+        let key = StateChangeKey {
+            service: service.to_string(),
+            timestamp,
+        };
+        Ok(self.triggers.get(&rtxn, &key)?)
+    }
+
     pub fn get_last_state(&self, name: &str) -> AnyResult<(SiteState, DateTime<Utc>)> {
         let rtxn = self.env.read_txn()?;
 
@@ -415,4 +618,192 @@ impl Database {
             Err(anyhow!("No state found"))
         }
     }
+
+    /// Deletes request history older than `before` (timestamp in seconds).
+    /// Returns the number of deleted entries.
+    pub fn prune_connection_history(&self, before: u64) -> AnyResult<usize> {
+        let mut wtxn = self.env.write_txn()?;
+        let deleted = self.connections.delete_range(&mut wtxn, &(..before))?;
+        wtxn.commit()?;
+
+        Ok(deleted)
+    }
+
+    /// Deletes all requests to a service from the request history.
+    /// Returns the number of deleted requests.
+    pub fn delete_connection_history(&self, service: &str) -> AnyResult<usize> {
+        let mut wtxn = self.env.write_txn()?;
+
+        let mut changed = Vec::new();
+        let mut deleted = 0;
+        for entry in self.connections.iter(&wtxn)? {
+            let (at, mut metadatas) = entry?;
+            let len = metadatas.len();
+            metadatas.retain(|metadata| metadata.service.as_deref() != Some(service));
+            if metadatas.len() != len {
+                deleted += len - metadatas.len();
+                changed.push((at, metadatas));
+            }
+        }
+
+        for (at, metadatas) in &changed {
+            if metadatas.is_empty() {
+                self.connections.delete(&mut wtxn, at)?;
+            } else {
+                self.connections.put(&mut wtxn, at, metadatas)?;
+            }
+        }
+        wtxn.commit()?;
+
+        Ok(deleted)
+    }
+
+    /// Deletes the state history of a service, including what triggered its starts.
+    /// Its last state is kept, like [`Database::prune_state_history`] does, so that its current state stays known.
+    /// Returns the number of deleted state changes.
+    pub fn delete_state_history(&self, service: &str) -> AnyResult<usize> {
+        let mut wtxn = self.env.write_txn()?;
+
+        let mut to_delete = Vec::new();
+        for entry in self.states.iter(&wtxn)? {
+            let (key, _) = entry?;
+            if key.service == service {
+                to_delete.push(key);
+            }
+        }
+        to_delete.pop();
+
+        for key in &to_delete {
+            self.states.delete(&mut wtxn, key)?;
+            self.triggers.delete(&mut wtxn, key)?;
+        }
+        wtxn.commit()?;
+
+        Ok(to_delete.len())
+    }
+
+    /// Deletes state history older than `before`, keeping the last state of each site before that date.
+    /// Returns the number of deleted entries.
+    pub fn prune_state_history(&self, before: DateTime<Utc>) -> AnyResult<usize> {
+        let mut wtxn = self.env.write_txn()?;
+
+        // An entry can be deleted when the next entry of the same site is also too old
+        let mut to_delete = Vec::new();
+        let mut previous: Option<StateChangeKey> = None;
+        for entry in self.states.iter(&wtxn)? {
+            let (key, _) = entry?;
+            if let Some(previous) = previous.take() {
+                if previous.service == key.service && key.timestamp < before {
+                    to_delete.push(previous);
+                }
+            }
+            previous = Some(key);
+        }
+
+        for key in &to_delete {
+            self.states.delete(&mut wtxn, key)?;
+            self.triggers.delete(&mut wtxn, key)?;
+        }
+        wtxn.commit()?;
+
+        Ok(to_delete.len())
+    }
+}
+
+/// Periodically deletes history entries older than the configured retention periods.
+pub async fn prune_history_task() {
+    loop {
+        let top_level = &current_config().top_level;
+
+        if let Some(retention) = top_level.history_retention {
+            let before = now().saturating_sub(retention);
+            match DATABASE.prune_connection_history(before) {
+                Ok(deleted) => debug!("Pruned {deleted} request history entries"),
+                Err(e) => error!("Couldn't prune request history: {e}"),
+            }
+        }
+
+        if let Some(retention) = top_level.state_history_retention {
+            let before = Utc::now() - Duration::from_secs(retention);
+            match DATABASE.prune_state_history(before) {
+                Ok(deleted) => debug!("Pruned {deleted} state history entries"),
+                Err(e) => error!("Couldn't prune state history: {e}"),
+            }
+        }
+
+        sleep(PRUNE_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database of its own, so that tests don't see each other's entries
+    fn test_database(map_size: usize) -> Database {
+        let path = tempfile::tempdir().unwrap().keep();
+        Database::open_at(path.to_str().unwrap(), map_size)
+    }
+
+    #[test]
+    fn full_database_is_reported_instead_of_panicking() {
+        let database = test_database(256 * 1024);
+        let states = [SiteState::Up, SiteState::Down];
+        let error = (0..100_000)
+            .find_map(|i| database.update_state(&format!("site-{i}"), states[i % 2]).err())
+            .expect("the database never filled up");
+        assert!(error.to_string().contains("database is full"), "{error}");
+    }
+
+    #[test]
+    fn percentile_picks_from_sorted_values() {
+        let values = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100];
+        assert_eq!(percentile(&values, 0), Some(10));
+        assert_eq!(percentile(&values, 50), Some(60));
+        assert_eq!(percentile(&values, 95), Some(100));
+        assert_eq!(percentile(&values, 100), Some(100));
+        assert_eq!(percentile::<u64>(&[], 50), None);
+    }
+
+    #[test]
+    fn start_duration_estimate_uses_the_history() {
+        let database = test_database(1024 * 1024);
+        assert!(database.get_start_duration_estimate("estimated", 10, 50).is_err());
+
+        let start = Utc::now() - Duration::from_secs(3600);
+        for (i, seconds) in [30, 10, 20].into_iter().enumerate() {
+            let starting = start + Duration::from_secs(i as u64 * 100);
+            database.put_state_at("estimated", SiteState::Starting, starting).unwrap();
+            database.put_state_at("estimated", SiteState::Up, starting + Duration::from_secs(seconds)).unwrap();
+        }
+        assert_eq!(database.get_start_duration_estimate("estimated", 10, 0).unwrap(), Duration::from_secs(10));
+        assert_eq!(database.get_start_duration_estimate("estimated", 10, 50).unwrap(), Duration::from_secs(20));
+        assert_eq!(database.get_start_duration_estimate("estimated", 10, 100).unwrap(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn deleting_state_history_keeps_the_current_state() {
+        let database = test_database(1024 * 1024);
+        let start = Utc::now() - Duration::from_secs(3600);
+        for (i, state) in [SiteState::Down, SiteState::Starting, SiteState::Up].into_iter().enumerate() {
+            database.put_state_at("deleted", state, start + Duration::from_secs(i as u64)).unwrap();
+        }
+        let state = database.get_last_state("deleted").unwrap();
+
+        assert_eq!(database.delete_state_history("deleted").unwrap(), 2);
+        assert_eq!(database.get_last_state("deleted").unwrap(), state);
+        assert_eq!(database.delete_state_history("deleted").unwrap(), 0);
+    }
+
+    #[test]
+    fn repeated_states_are_recorded_once() {
+        let database = test_database(1024 * 1024);
+        for state in [SiteState::Up, SiteState::Up, SiteState::Down] {
+            database.update_state("repeated", state).unwrap();
+        }
+
+        let history = database.get_state_history_since("repeated", DateTime::from_timestamp_nanos(0)).unwrap();
+        let states: Vec<SiteState> = history.into_iter().map(|(_, state)| state).collect();
+        assert_eq!(states, [SiteState::Up, SiteState::Down]);
+    }
 }
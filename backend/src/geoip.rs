@@ -0,0 +1,44 @@
+//! Resolves client IPs to countries using the MMDB file of `geoip_database`, for the request history.
+
+use std::{net::IpAddr, sync::RwLock};
+use anyhow::anyhow;
+use log::*;
+use maxminddb::{PathElement, Reader};
+use crate::Config;
+
+/// A loaded database, along with its path so that reloads only reopen it when it changes
+struct GeoIpDatabase {
+    path: &'static str,
+    reader: Reader<Vec<u8>>,
+}
+
+static GEOIP_DATABASE: RwLock<Option<&'static GeoIpDatabase>> = RwLock::new(None);
+
+/// Opens the database of `geoip_database`, unless it is already loaded.
+pub fn load_geoip_database(config: &'static Config) -> anyhow::Result<()> {
+    let Some(path) = config.top_level.geoip_database.as_deref() else {
+        *GEOIP_DATABASE.write().expect("geoip lock poisoned") = None;
+        return Ok(());
+    };
+    if GEOIP_DATABASE.read().expect("geoip lock poisoned").is_some_and(|database| database.path == path) {
+        return Ok(());
+    }
+
+    let reader = Reader::open_readfile(path).map_err(|e| anyhow!("could not open geoip_database {path}: {e}"))?;
+    debug!("Loaded GeoIP database {path} ({})", reader.metadata().database_type);
+    *GEOIP_DATABASE.write().expect("geoip lock poisoned") = Some(Box::leak(Box::new(GeoIpDatabase { path, reader })));
+    Ok(())
+}
+
+/// Looks up the ISO country code of an IP, if a database is loaded and knows it.
+pub fn country(ip: &str) -> Option<String> {
+    let database = (*GEOIP_DATABASE.read().expect("geoip lock poisoned"))?;
+    let ip: IpAddr = ip.parse().ok()?;
+    match database.reader.lookup(ip).and_then(|result| result.decode_path(&[PathElement::Key("country"), PathElement::Key("iso_code")])) {
+        Ok(country) => country,
+        Err(e) => {
+            debug!("GeoIP lookup of {ip} failed: {e}");
+            None
+        }
+    }
+}
@@ -1,63 +1,165 @@
 use std::{path::Path, time::Duration};
 use log::*;
+use serde::Serialize;
 use tokio::{fs, io::AsyncWriteExt, net::TcpStream};
 
+/// Reads the landing page (index.html) and replaces its template variables.
+/// When a progress URL is given, the page polls it instead of reloading itself to check if the site is up.
+pub async fn render_landing_page(
+    landing_folder: &str,
+    site_name: &str,
+    done: Duration,
+    duration: Duration,
+    keep_alive: u64,
+    progress_url: Option<&str>,
+) -> std::io::Result<String> {
+    let index_path = Path::new(landing_folder).join("index.html");
+    let content = fs::read_to_string(&index_path).await?;
+
+    Ok(content
+        .replace("DONE_MS", &done.as_millis().to_string())
+        .replace("DURATION_MS", &duration.as_millis().to_string())
+        .replace("KEEP_ALIVE", &keep_alive.to_string())
+        .replace("SITE_NAME", site_name)
+        .replace("PROGRESS_URL", &serde_json::to_string(&progress_url).unwrap_or_else(|_| String::from("null"))))
+}
+
 /// Serves the landing page (index.html) with replaced template variables
 pub async fn serve_landing_page(
     mut stream: TcpStream,
     landing_folder: &str,
+    site_name: &str,
     done: Duration,
     duration: Duration,
     keep_alive: u64,
+    progress_url: Option<&str>,
 ) -> bool {
-    let index_path = Path::new(landing_folder).join("index.html");
-
-    // Read the index.html file
-    let content = match fs::read_to_string(&index_path).await {
+    let content = match render_landing_page(landing_folder, site_name, done, duration, keep_alive, progress_url).await {
         Ok(content) => content,
         Err(e) => {
-            warn!("Could not read index.html from {:?}: {e}", index_path);
+            warn!("Could not read index.html from {landing_folder}: {e}");
             send_error(&mut stream, 500, "Landing page not found").await;
             return false;
         }
     };
 
-    // Replace template variables
-    let content = content
-        .replace("DONE_MS", &done.as_millis().to_string())
-        .replace("DURATION_MS", &duration.as_millis().to_string())
-        .replace("KEEP_ALIVE", &keep_alive.to_string());
-
     // Send response
     let status_line = "HTTP/1.1 503 Service Unavailable";
-    let retry_after = duration.checked_sub(done)
-        .and_then(|remaining| {
-            let remaining_secs = remaining.as_secs();
-            if remaining_secs > 0 {
-                Some(format!("Retry-After: {remaining_secs}\r\n"))
-            } else {
-                None
-            }
-        })
+    let retry_after = remaining_secs(done, duration)
+        .map(|remaining_secs| format!("Retry-After: {remaining_secs}\r\n"))
         .unwrap_or_default();
     
     let length = content.len();
     let response = format!(
-        "{status_line}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {length}\r\n{retry_after}\r\n{content}"
+        "{status_line}\r\nConnection: close\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {length}\r\n{retry_after}\r\n{content}"
     );
 
     if let Err(e) = stream.write_all(response.as_bytes()).await {
         warn!("Could not write landing page response: {e}");
         return false;
     }
+    let _ = stream.shutdown().await;
 
     true
 }
 
+#[derive(Serialize)]
+struct StartingResponse {
+    status: &'static str,
+    retry_after: u64,
+}
+
+/// Serves a JSON 503 response telling the site is starting, for clients that aren't browsers
+pub async fn serve_starting_json(mut stream: TcpStream, done: Duration, duration: Duration) -> bool {
+    let remaining_secs = remaining_secs(done, duration);
+    let content = serde_json::to_string(&StartingResponse { status: "starting", retry_after: remaining_secs.unwrap_or(0) }).unwrap_or_default();
+    let retry_after = remaining_secs
+        .map(|remaining_secs| format!("Retry-After: {remaining_secs}\r\n"))
+        .unwrap_or_default();
+
+    let length = content.len();
+    let response = format!(
+        "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Type: application/json\r\nCache-Control: no-store\r\nContent-Length: {length}\r\n{retry_after}\r\n{content}"
+    );
+
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        warn!("Could not write starting response: {e}");
+        return false;
+    }
+    let _ = stream.shutdown().await;
+
+    true
+}
+
+/// Seconds left before the site is expected to be up, if any
+fn remaining_secs(done: Duration, duration: Duration) -> Option<u64> {
+    duration.checked_sub(done).map(|remaining| remaining.as_secs()).filter(|remaining_secs| *remaining_secs > 0)
+}
+
 async fn send_error(stream: &mut TcpStream, code: u16, message: &str) {
     let status_line = format!("HTTP/1.1 {code} {message}");
     let content = message;
     let length = content.len();
-    let response = format!("{status_line}\r\nContent-Type: text/plain\r\nContent-Length: {length}\r\n\r\n{content}");
+    let response = format!("{status_line}\r\nConnection: close\r\nContent-Type: text/plain\r\nContent-Length: {length}\r\n\r\n{content}");
     let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+    use crate::test_utils::*;
+    use super::*;
+
+    /// A connection to serve on, and the client end to read the response from
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    async fn read_response(mut client: TcpStream) -> Response {
+        let mut raw = Vec::new();
+        client.read_to_end(&mut raw).await.unwrap();
+        Response::parse(&raw)
+    }
+
+    #[tokio::test]
+    async fn landing_page_responses_are_well_formed() {
+        let env = TestEnv::new();
+        let landing_folder = env.path("landing");
+
+        let (server, client) = connection().await;
+        assert!(serve_landing_page(server, &landing_folder, "landing", Duration::from_secs(2), Duration::from_secs(10), 60, Some("/progress")).await);
+        let response = read_response(client).await;
+        assert_eq!(response.status, 503);
+        assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.header("retry-after"), Some("8"));
+        assert_eq!(response.text(), "<h1>landing</h1><p>60 2000 10000 \"/progress\"</p>");
+
+        let (server, client) = connection().await;
+        assert!(serve_landing_page(server, &landing_folder, "landing", Duration::from_secs(10), Duration::from_secs(10), 60, None).await);
+        let response = read_response(client).await;
+        assert_eq!(response.status, 503);
+        assert_eq!(response.header("retry-after"), None);
+        assert_eq!(response.text(), "<h1>landing</h1><p>60 10000 10000 null</p>");
+    }
+
+    #[tokio::test]
+    async fn starting_json_responses_are_well_formed() {
+        let (server, client) = connection().await;
+        assert!(serve_starting_json(server, Duration::from_secs(1), Duration::from_secs(5)).await);
+        let response = read_response(client).await;
+        assert_eq!(response.status, 503);
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        assert_eq!(response.header("retry-after"), Some("4"));
+        assert_eq!(response.text(), r#"{"status":"starting","retry_after":4}"#);
+
+        let (server, client) = connection().await;
+        assert!(serve_starting_json(server, Duration::ZERO, Duration::ZERO).await);
+        let response = read_response(client).await;
+        assert_eq!(response.header("retry-after"), None);
+        assert_eq!(response.text(), r#"{"status":"starting","retry_after":0}"#);
+    }
 }
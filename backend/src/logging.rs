@@ -0,0 +1,33 @@
+//! Logging setup. Logs are human-readable by default, and JSON lines when `HIBERNATOR_LOG_FORMAT=json` is set.
+
+use std::io::Write;
+use chrono::Utc;
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::{Map, Value as JsonValue};
+
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.as_str().to_string(), JsonValue::String(value.to_string()));
+        Ok(())
+    }
+}
+
+pub fn init_logging() {
+    let mut builder = env_logger::Builder::from_default_env();
+
+    if std::env::var("HIBERNATOR_LOG_FORMAT").is_ok_and(|format| format == "json") {
+        builder.format(|buf, record| {
+            let mut fields = Map::new();
+            fields.insert(String::from("timestamp"), JsonValue::String(Utc::now().to_rfc3339()));
+            fields.insert(String::from("level"), JsonValue::String(record.level().to_string()));
+            fields.insert(String::from("target"), JsonValue::String(record.target().to_string()));
+            fields.insert(String::from("message"), JsonValue::String(record.args().to_string()));
+            let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+            writeln!(buf, "{}", JsonValue::Object(fields))
+        });
+    }
+
+    builder.init();
+}
@@ -1,6 +1,10 @@
-use std::{fs::metadata, os::unix::fs::MetadataExt, path::Path};
+use std::{sync::LazyLock, time::Duration};
 use log::*;
-use tokio::spawn;
+use tokio::{signal::unix::{signal, SignalKind}, spawn};
+use crate::{database::DATABASE, shutdown::{request_shutdown, wait_for_connections}};
+
+/// How long active connections are given to finish when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
 mod config;
 use config::*;
@@ -14,111 +18,102 @@ mod database;
 mod api;
 mod bincoded;
 mod landing;
+mod reload;
+use reload::*;
+mod logging;
+use logging::*;
+mod webhook;
+mod memory;
+mod request_log;
+mod geoip;
+mod status;
+mod shutdown;
+#[cfg(test)]
+mod test_utils;
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() { 
-    env_logger::init();
-
-    let config_path = std::env::args().nth(1).unwrap_or(String::from("config.toml"));
-
-    #[cfg(target_family = "unix")]
-    {
-        let metadata = metadata(&config_path).expect("could not read config file metadata");
-        let uid = metadata.uid();
-        let mode = metadata.mode();
-        let current_uid = unsafe { libc::getuid() };
-
-        if uid != current_uid {
-            panic!("Config file should be owned by current user");
+async fn main() {
+    LazyLock::force(&api::STARTED_AT);
+    init_logging();
+
+    // Config mistakes are the most common, so they get a readable message rather than a panic
+    let config = match load_config(&config_source()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
         }
-    
-        if mode & 0o002 != 0 {
-            panic!("Config file should not be writable by other users");
-        }
-    }
-
-    let config_data = std::fs::read_to_string(config_path).expect("could not read config file");
-    let config: Config = toml::from_str(&config_data).expect("could not parse config file");
-    let config = Box::leak(Box::new(config));
-
-    info!("Starting hibernator: managing {} sites", config.sites.len());
-
-    // Make sure every access log exists
-    for site_config in &config.sites {
-        if !Path::new(&site_config.access_log).exists() {
-            panic!("Site {} access log doesn't exist at {}", site_config.name, site_config.access_log);
-        }
-    }
-
-    // Make sure every hibernator config exists
-    for site_config in &config.sites {
-        if !Path::new(&site_config.nginx_hibernator_config()).exists() {
-            panic!("Site {} hibernator config doesn't exist at {}", site_config.name, site_config.nginx_hibernator_config());
+    };
+    if check_mode() {
+        match check_environment(&config).await {
+            Ok(()) => println!("Config is valid"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
         }
+        return;
     }
-
-    // Make sure every site has at least one host
-    for site_config in &config.sites {
-        if site_config.hosts.is_empty() {
-            panic!("Site {} must have at least one host", site_config.name);
+    if status::status_mode() {
+        if let Err(e) = status::print_status(&config).await {
+            eprintln!("{e}");
+            std::process::exit(1);
         }
+        return;
     }
-
-    // Make sure a site doesn't have blacklist_ips and whitelist_ips at the same time
-    for site_config in &config.sites {
-        if site_config.ip_blacklist.is_some() && site_config.ip_whitelist.is_some() {
-            panic!("Site {} cannot have both blacklist_ips and whitelist_ips", site_config.name);
-        }
+    if let Err(e) = check_programs(&config) {
+        error!("{e}");
+        std::process::exit(1);
     }
-
-    // Make sure the whitelists are not empty if they exist
-    for site_config in &config.sites {
-        if let Some(whitelist_ips) = &site_config.ip_whitelist {
-            if whitelist_ips.is_empty() {
-                panic!("Site {} whitelist_ips cannot be empty", site_config.name);
-            }
-        }
+    let config = Box::leak(Box::new(config));
+    if let Err(e) = geoip::load_geoip_database(config) {
+        error!("{e}");
+        std::process::exit(1);
     }
+    set_current_config(config);
 
-    // Make sure every site has an index.html in its landing folder
-    for site_config in &config.sites {
-        let landing_folder = site_config.landing_folder(config);
-        let index_path = Path::new(landing_folder).join("index.html");
-        if !index_path.exists() {
-            panic!(
-                "Site {} landing page doesn't exist at {:?}. Expected index.html in landing folder: {}",
-                site_config.name, index_path, landing_folder
-            );
-        }
-    }
+    info!("Starting hibernator: managing {} sites", config.sites.len());
 
     setup_server(config).await;
 
     info!("Hibernator started");
 
     // Start all site tasks
-    let mut controllers = Vec::new();
-    let mut channels = Vec::new();
-    for site_config in &config.sites {
-        let (controller, start_receiver, started_sender) = SiteController::new(site_config).await;
-
-        controllers.push(controller);
-        channels.push((start_receiver, started_sender));
+    start_controllers(config).await;
+
+    spawn(database::prune_history_task());
+    spawn(memory::memory_pressure_task());
+
+    // Reload the config on SIGHUP, and shut down on SIGTERM or SIGINT
+    let mut hangup = signal(SignalKind::hangup()).expect("could not listen for SIGHUP");
+    let mut terminate = signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("could not listen for SIGINT");
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading config");
+                if let Err(e) = reload_config().await {
+                    error!("Could not reload config: {e}");
+                }
+            }
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
     }
 
-    let controllers: &_ = controllers.leak();
-    unsafe {
-        SITE_CONTROLLERS = controllers;
+    info!("Shutting down");
+    request_shutdown();
+    let remaining = wait_for_connections(SHUTDOWN_TIMEOUT).await;
+    if remaining > 0 {
+        warn!("Shutting down with {remaining} connections still active");
     }
 
-    let mut handles = Vec::new();
-    for ((start_receiver, started_sender), controller) in channels.into_iter().zip(controllers) {
-        let handle = controller.handle(start_receiver, started_sender);
-        handles.push(spawn(handle));
+    // The hibernator won't know what happens to sites until it restarts
+    for controller in site_controllers() {
+        if let Err(e) = DATABASE.update_state(&controller.config.name, SiteState::Unknown) {
+            error!("Could not record final state of {}: {e}", controller.config.name);
+        }
     }
 
-    // Join all handles
-    for handle in handles {
-        let _  = handle.await;
-    }
+    info!("Hibernator stopped");
 }
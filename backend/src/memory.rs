@@ -0,0 +1,66 @@
+//! Stops the least recently active sites when the system runs low on memory, according to `memory_pressure`.
+
+use std::time::Duration;
+use anyhow::anyhow;
+use chrono::Utc;
+use log::*;
+use tokio::{fs::read_to_string, time::sleep};
+use crate::{config::current_config, controller::site_controllers, shutdown::shutdown_requested};
+
+/// How often to look at the config again when memory pressure handling is disabled
+const DISABLED_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reads the percentage of the system memory in use from `/proc/meminfo`
+async fn used_memory_percent() -> anyhow::Result<u64> {
+    let meminfo = read_to_string("/proc/meminfo").await?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+            .ok_or(anyhow!("no {name} in /proc/meminfo"))
+    };
+
+    let total = field("MemTotal")?;
+    let available = field("MemAvailable")?;
+    if total == 0 {
+        return Err(anyhow!("MemTotal is 0 in /proc/meminfo"));
+    }
+    Ok(total.saturating_sub(available) * 100 / total)
+}
+
+/// Periodically stops the awake site with the oldest activity while memory usage is above the high-water mark.
+pub async fn memory_pressure_task() {
+    loop {
+        let interval = match &current_config().top_level.memory_pressure {
+            Some(memory_pressure) => {
+                match used_memory_percent().await {
+                    Ok(used) if used > memory_pressure.max_used_percent as u64 => {
+                        let now = Utc::now();
+                        let candidate = site_controllers()
+                            .iter()
+                            .filter(|controller| controller.config.enabled() && controller.config.hibernates() && controller.get_state().is_up())
+                            .filter(|controller| controller.min_uptime_end().is_none_or(|min_uptime_end| min_uptime_end <= now))
+                            .min_by_key(|controller| controller.last_activity());
+                        match candidate {
+                            Some(controller) => {
+                                warn!("Memory usage is at {used}%, stopping site {} which was the least recently active", controller.config.name);
+                                controller.request_stop();
+                            }
+                            None => debug!("Memory usage is at {used}%, but no site can be stopped"),
+                        }
+                    }
+                    Ok(used) => trace!("Memory usage is at {used}%"),
+                    Err(e) => error!("Could not read memory usage: {e}"),
+                }
+                Duration::from_secs(memory_pressure.check_interval())
+            }
+            None => DISABLED_INTERVAL,
+        };
+
+        tokio::select! {
+            _ = sleep(interval) => (),
+            _ = shutdown_requested() => return,
+        }
+    }
+}
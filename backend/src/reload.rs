@@ -0,0 +1,93 @@
+//! Config reloading, shared by the SIGHUP handler and the `/hibernator-api/reload` endpoint.
+
+use log::*;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use crate::{config::*, geoip::load_geoip_database, util::check_programs, controller::{set_site_controllers, site_controllers, SiteController}};
+
+static RELOAD_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+/// Spawns a controller for every site of the config.
+pub async fn start_controllers(config: &'static Config) {
+    let mut controllers = Vec::new();
+    for site_config in &config.sites {
+        controllers.push(SiteController::spawn(site_config).await);
+    }
+    set_site_controllers(controllers);
+}
+
+/// Site configs contain compiled globs, so they are compared through their serialized form.
+fn same_site_config(a: &SiteConfig, b: &SiteConfig) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Re-reads the config file and applies it.
+/// Controllers of unchanged sites keep running, the others get replaced.
+/// Nothing is applied if the new config is invalid.
+pub async fn reload_config() -> anyhow::Result<ReloadSummary> {
+    let _guard = RELOAD_LOCK.lock().await;
+
+    let config = load_config(&config_source())?;
+    check_programs(&config)?;
+    let config: &'static Config = Box::leak(Box::new(config));
+    load_geoip_database(config)?;
+    let old_config = current_config();
+    let old_controllers = site_controllers();
+
+    if config.top_level.hibernator_port() != old_config.top_level.hibernator_port() {
+        warn!("Changing hibernator_port requires a restart");
+    }
+    if config.top_level.bind_address() != old_config.top_level.bind_address() {
+        warn!("Changing bind_address requires a restart");
+    }
+    if config.top_level.database_path() != old_config.top_level.database_path() {
+        warn!("Changing database_path requires a restart");
+    }
+
+    let mut summary = ReloadSummary::default();
+    let mut controllers = Vec::new();
+    for site_config in &config.sites {
+        let old_controller = old_controllers.iter().find(|controller| controller.config.name == site_config.name);
+        match old_controller {
+            Some(old_controller) if same_site_config(old_controller.config, site_config) => {
+                controllers.push(*old_controller);
+            }
+            Some(old_controller) => {
+                old_controller.abort();
+                controllers.push(SiteController::spawn(site_config).await);
+                summary.changed.push(site_config.name.clone());
+            }
+            None => {
+                controllers.push(SiteController::spawn(site_config).await);
+                summary.added.push(site_config.name.clone());
+            }
+        }
+    }
+
+    for old_controller in old_controllers {
+        if !config.sites.iter().any(|site_config| site_config.name == old_controller.config.name) {
+            old_controller.abort();
+            summary.removed.push(old_controller.config.name.clone());
+        }
+    }
+
+    set_current_config(config);
+    set_site_controllers(controllers);
+
+    info!(
+        "Config reloaded: {} added, {} removed, {} changed",
+        summary.added.len(), summary.removed.len(), summary.changed.len()
+    );
+
+    Ok(summary)
+}
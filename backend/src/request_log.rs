@@ -0,0 +1,101 @@
+//! The hibernator's own access log, written to `access_log_output` with one line per handled request.
+
+use std::time::Duration;
+use anyhow::bail;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::*;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use crate::{server::ConnectionMetadata, Config};
+
+pub const DEFAULT_FORMAT: &str = "$time $client \"$method $url\" $host $service $result $status ${duration_ms}ms";
+
+const VARIABLES: &[&str] = &[
+    "time", "client", "method", "url", "host", "service", "result", "status", "cold", "browser", "duration_ms", "wake_wait_ms", "upstream_ms",
+];
+
+/// Splits a format string into literal text and `$variable` or `${variable}` parts
+fn parse_format(format: &str) -> Vec<(bool, &str)> {
+    let mut parts = Vec::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('$') {
+        parts.push((false, &rest[..start]));
+        rest = &rest[start + 1..];
+
+        let (name, remaining) = match rest.strip_prefix('{').and_then(|braced| braced.split_once('}')) {
+            Some((name, remaining)) => (name, remaining),
+            None => {
+                let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        parts.push((true, name));
+        rest = remaining;
+    }
+    parts.push((false, rest));
+    parts
+}
+
+/// Makes sure a format string only uses known variables
+pub fn validate_format(format: &str) -> anyhow::Result<()> {
+    for (is_variable, name) in parse_format(format) {
+        if is_variable && !VARIABLES.contains(&name) {
+            bail!("unknown variable ${name} in access_log_format (available: {})", VARIABLES.join(", "));
+        }
+    }
+    Ok(())
+}
+
+fn format_line(format: &str, at: DateTime<Utc>, duration: Duration, metadata: &ConnectionMetadata) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| String::from("-"));
+    let host = metadata
+        .request
+        .iter()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_string());
+
+    let mut line = String::new();
+    for (is_variable, part) in parse_format(format) {
+        if !is_variable {
+            line.push_str(part);
+            continue;
+        }
+        let value = match part {
+            "time" => at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "client" => optional(metadata.real_ip.clone()),
+            "method" => metadata.method.clone(),
+            "url" => metadata.url.clone(),
+            "host" => optional(host.clone()),
+            "service" => optional(metadata.service.clone()),
+            "result" => metadata.result.as_str().to_string(),
+            "status" => optional(metadata.status.map(|status| status.to_string())),
+            "cold" => metadata.cold.to_string(),
+            "browser" => metadata.is_browser.to_string(),
+            "duration_ms" => duration.as_millis().to_string(),
+            "wake_wait_ms" => optional(metadata.wake_wait_ms.map(|ms| ms.to_string())),
+            "upstream_ms" => optional(metadata.upstream_ms.map(|ms| ms.to_string())),
+            _ => String::from("-"),
+        };
+        line.push_str(&value);
+    }
+    line.push('\n');
+    line
+}
+
+/// Appends a line for a handled request to `access_log_output`, if set.
+/// The file is reopened for every line so that it can be rotated freely.
+pub async fn log_request(config: &Config, at: DateTime<Utc>, duration: Duration, metadata: &ConnectionMetadata) {
+    let Some(path) = &config.top_level.access_log_output else {
+        return;
+    };
+
+    let line = format_line(config.top_level.access_log_format(), at, duration, metadata);
+    let result = async {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+        file.write_all(line.as_bytes()).await
+    }.await;
+    if let Err(e) = result {
+        warn!("Could not write to access_log_output {path}: {e}");
+    }
+}
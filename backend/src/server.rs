@@ -1,11 +1,15 @@
-use std::time::Duration;
-use crate::{landing, Config, ProxyMode, SiteConfig, api::handle_api_request, controller::SiteController, database::DATABASE, get_controller, util::now};
+use std::{sync::OnceLock, time::{Duration, Instant}};
+use crate::{config::current_config, geoip, landing, request_log, Config, ProxyMode, SiteConfig, api::{handle_api_request, progress_url}, controller::{get_controller_by_name, SiteController, SiteState, TriggerSource}, database::DATABASE, get_controller, util::{is_healthy, parse_ip, truncate_str, UpstreamStream}, shutdown::{shutdown_requested, ConnectionGuard}};
 use log::*;
 use anyhow::anyhow;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, spawn, time::{sleep, timeout}};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, spawn, time::{sleep, timeout}};
 use tokio_stream::{wrappers::LinesStream, StreamExt};
 
+/// How long the single proxy attempt made to starting sites with `ProxyMode::WhenStarting` can take
+const STARTING_PROXY_TIMEOUT_MS: u64 = 1000;
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum ConnectionResult {
     MissingHost,
@@ -17,6 +21,26 @@ pub enum ConnectionResult {
     ProxyFailed,
     ProxyTimeout,
     ApiHandled,
+    BodyTooLarge,
+    InvalidContentLength,
+}
+
+impl ConnectionResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionResult::MissingHost => "MissingHost",
+            ConnectionResult::UnknownSite => "UnknownSite",
+            ConnectionResult::InvalidUrl => "InvalidUrl",
+            ConnectionResult::Ignored => "Ignored",
+            ConnectionResult::Unproxied => "Unproxied",
+            ConnectionResult::ProxySuccess => "ProxySuccess",
+            ConnectionResult::ProxyFailed => "ProxyFailed",
+            ConnectionResult::ProxyTimeout => "ProxyTimeout",
+            ConnectionResult::ApiHandled => "ApiHandled",
+            ConnectionResult::BodyTooLarge => "BodyTooLarge",
+            ConnectionResult::InvalidContentLength => "InvalidContentLength",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,13 +52,26 @@ pub struct ConnectionMetadata {
     pub real_ip: Option<String>,
     pub method: String,
     pub url: String,
+    /// Status code returned by the upstream, when the request was proxied
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// Whether the site wasn't up when the request arrived
+    #[serde(default)]
+    pub cold: bool,
+    /// Time spent waiting for the site to start, when the request was held for the proxy
+    #[serde(default)]
+    pub wake_wait_ms: Option<u64>,
+    /// Time spent trying to get a response from the upstream once the site started
+    #[serde(default)]
+    pub upstream_ms: Option<u64>,
+    /// ISO code of the country of the client, when `geoip_database` is set
+    #[serde(default)]
+    pub country: Option<String>,
 }
 
 impl ConnectionMetadata {
-    fn new(mut request: Vec<String>, result: ConnectionResult, is_browser: bool, real_ip: Option<String>) -> Self {
-        // TODO: Limits used here should be configurable
-        
-        // Extract and remove the request line (method, URL, protocol)
+    fn new(request: Vec<String>, result: ConnectionResult, is_browser: bool, real_ip: Option<String>) -> Self {
+        // Extract the request line (method, URL, protocol)
         let (method, url) = if let Some(first_line) = request.first() {
             let parts: Vec<&str> = first_line.split_whitespace().collect();
             let method = parts.first().unwrap_or(&"-").to_string();
@@ -43,30 +80,54 @@ impl ConnectionMetadata {
         } else {
             ("-".to_string(), "-".to_string())
         };
-        
+
+        let country = real_ip.as_deref().and_then(geoip::country);
+
+        ConnectionMetadata { request, result, service: None, is_browser, real_ip, method, url, status: None, cold: false, wake_wait_ms: None, upstream_ms: None, country }
+    }
+
+    /// Limits the stored request according to the history settings of the site, if any, or the global ones
+    fn trim_request(&mut self, config: &Config, site_config: Option<&SiteConfig>) {
+        // Only keep lines until empty line
+        if let Some(empty_idx) = self.request.iter().position(|line| line.is_empty()) {
+            self.request.drain(empty_idx..self.request.len());
+        }
+
+        if let Some(site_config) = site_config.filter(|site_config| site_config.history_verbatim_headers) {
+            // Keep everything up to the byte cap
+            let mut remaining = site_config.history_verbatim_max_bytes();
+            self.request.retain_mut(|line| {
+                truncate_str(line, remaining);
+                remaining -= line.len();
+                !line.is_empty()
+            });
+            return;
+        }
+
         // Remove the request line and X-Real-IP header since they're stored separately
-        request.retain(|line| {
+        self.request.retain(|line| {
             let line_lower = line.to_lowercase();
             !line_lower.starts_with("x-real-ip:") && 
-            !line.split_whitespace().collect::<Vec<_>>().get(0).map_or(false, |first| 
-                matches!(*first, "GET" | "POST" | "PUT" | "DELETE" | "PATCH" | "HEAD" | "OPTIONS" | "CONNECT" | "TRACE")
+            !line.split_whitespace().next().is_some_and(|first| 
+                matches!(first, "GET" | "POST" | "PUT" | "DELETE" | "PATCH" | "HEAD" | "OPTIONS" | "CONNECT" | "TRACE")
             )
         });
-        
-        // Only keep lines until empty line
-        if let Some(empty_idx) = request.iter().position(|line| line.is_empty()) {
-            request.drain(empty_idx..request.len());
-        }
 
-        // Only keep 8kB per line
-        for line in &mut request {
-            line.truncate(2_000);
+        // Truncate long lines
+        let max_line_bytes = match site_config {
+            Some(site_config) => site_config.history_max_line_bytes(config),
+            None => config.top_level.history_max_line_bytes(),
+        };
+        for line in &mut self.request {
+            truncate_str(line, max_line_bytes);
         }
 
-        // Only keep 30 lines
-        request.truncate(30);
-
-        ConnectionMetadata { request, result, service: None, is_browser, real_ip, method, url }
+        // Limit the number of lines
+        let max_header_lines = match site_config {
+            Some(site_config) => site_config.history_max_header_lines(config),
+            None => config.top_level.history_max_header_lines(),
+        };
+        self.request.truncate(max_header_lines);
     }
 
     fn with_controller(mut self, controller: &SiteController) -> Self {
@@ -74,6 +135,22 @@ impl ConnectionMetadata {
         self
     }
 
+    fn with_cold(mut self, cold: bool) -> Self {
+        self.cold = cold;
+        self
+    }
+
+    fn with_status(mut self, status: Option<u16>) -> Self {
+        self.status = status;
+        self
+    }
+
+    fn with_timings(mut self, wake_wait: Duration, upstream: Option<Duration>) -> Self {
+        self.wake_wait_ms = Some(wake_wait.as_millis() as u64);
+        self.upstream_ms = upstream.map(|upstream| upstream.as_millis() as u64);
+        self
+    }
+
     fn api_handled() -> Self {
         ConnectionMetadata {
             request: Vec::new(),
@@ -83,26 +160,47 @@ impl ConnectionMetadata {
             real_ip: None,
             method: "-".to_string(),
             url: "-".to_string(),
+            status: None,
+            cold: false,
+            wake_wait_ms: None,
+            upstream_ms: None,
+            country: None,
         }
     }
 }
 
 pub async fn setup_server(config: &'static Config) {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", config.top_level.hibernator_port())).await.expect("Could not bind to port");
+    let listener = TcpListener::bind((config.top_level.bind_address(), config.top_level.hibernator_port())).await.expect("Could not bind to port");
 
     spawn(async move {
         loop {
-            if let Ok((stream, _addr)) = listener.accept().await {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown_requested() => break,
+            };
+            if let Ok((stream, _addr)) = accepted {
                 spawn(async move {
-                    let at = now();
-                    let result = handle_connection(stream, config).await;
+                    let _guard = ConnectionGuard::acquire();
+                    let received_at = Utc::now();
+                    let started = Instant::now();
+                    let config = current_config();
+                    let mut result = handle_connection(stream, config).await;
 
                     if result.result == ConnectionResult::ApiHandled {
                         return;
                     }
 
-                    if let Err(e) = DATABASE.put_connection_metadata(at, result) {
-                        eprintln!("Couldn't put connection metadata {e}")
+                    request_log::log_request(config, received_at, started.elapsed(), &result).await;
+
+                    let controller = result.service.as_deref().and_then(get_controller_by_name);
+                    if let Some(controller) = controller.filter(|_| result.result == ConnectionResult::ProxySuccess) {
+                        controller.record_served_request();
+                    }
+                    let site_config = controller.map(|controller| controller.config);
+                    result.trim_request(config, site_config);
+
+                    if let Err(e) = DATABASE.put_connection_metadata(received_at.timestamp() as u64, result) {
+                        error!("Couldn't put connection metadata: {e}")
                     }
                 });
             }
@@ -110,39 +208,185 @@ pub async fn setup_server(config: &'static Config) {
     });
 }
 
+fn header_value<'a>(http_request: &'a [String], name: &str) -> Option<&'a str> {
+    http_request
+        .iter()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Finds the client IP in the headers set by the reverse proxy.
+fn extract_real_ip(http_request: &[String], config: &Config) -> Option<String> {
+    let from_header = |name: &str| {
+        let value = header_value(http_request, name)?;
+        let value = match name.eq_ignore_ascii_case("x-forwarded-for") {
+            true => value.split(',').next()?.trim(),
+            false => value,
+        };
+        Some(value.to_string()).filter(|value| !value.is_empty())
+    };
+
+    match &config.top_level.trusted_proxy_ip_header {
+        Some(header) => from_header(header),
+        None => from_header("x-real-ip").or_else(|| from_header("x-forwarded-for")),
+    }
+}
+
 fn should_be_processed(site_config: &'static SiteConfig, path: &str, real_ip: Option<&str>) -> bool {
-    if let Some(blacklist_paths) = &site_config.path_blacklist {
-        for blacklist_path in blacklist_paths {
-            if blacklist_path.is_match(path) {
-                return false;
-            }
-        }
+    if site_config.is_path_blacklisted(path) {
+        return false;
+    }
+
+    if !site_config.is_wake_path(path) {
+        return false;
     }
 
+    is_ip_processed(site_config, real_ip)
+}
+
+/// Whether a request to a blacklisted path should still warm the site up, according to `wake_on_blacklisted_paths`
+fn should_warm(site_config: &'static SiteConfig, path: &str, real_ip: Option<&str>) -> bool {
+    site_config.wake_on_blacklisted_paths() && site_config.is_path_blacklisted(path) && is_ip_processed(site_config, real_ip)
+}
+
+fn is_ip_processed(site_config: &'static SiteConfig, real_ip: Option<&str>) -> bool {
+    let real_ip = real_ip.and_then(parse_ip);
+
     if let Some(blacklist_ips) = &site_config.ip_blacklist {
-        let real_ip = real_ip.unwrap();
-        for blacklist_ip in blacklist_ips {
-            if real_ip.starts_with(blacklist_ip) {
-                return false;
-            }
+        if real_ip.is_some_and(|real_ip| blacklist_ips.iter().any(|blacklist_ip| blacklist_ip.contains(&real_ip))) {
+            return false;
         }
     }
 
     if let Some(whitelist_ips) = &site_config.ip_whitelist {
-        let real_ip = real_ip.unwrap();
-        for whitelist_ip in whitelist_ips {
-            if real_ip.starts_with(whitelist_ip) {
-                return true;
+        return real_ip.is_some_and(|real_ip| whitelist_ips.iter().any(|whitelist_ip| whitelist_ip.contains(&real_ip)));
+    }
+
+    true
+}
+
+/// Writes a whole response and closes the connection, as the hibernator serves a single request per connection
+async fn write_response(stream: &mut TcpStream, response: &[u8]) {
+    let _ = stream.write_all(response).await;
+    let _ = stream.shutdown().await;
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        410 => "Gone",
+        421 => "Misdirected Request",
+        444 => "No Response",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Answers a request that no site handles, with the response configured at the top level
+async fn write_unknown_site_response(stream: &mut TcpStream, config: &Config) {
+    let status = config.top_level.unknown_site_status();
+    let (content_type, content) = match &config.top_level.unknown_site_body_file {
+        Some(body_file) => match tokio::fs::read(body_file).await {
+            Ok(content) if body_file.ends_with(".html") => ("text/html", content),
+            Ok(content) => ("text/plain", content),
+            Err(e) => {
+                warn!("Could not read unknown_site_body_file {body_file}: {e}");
+                ("text/plain", b"Not Found".to_vec())
+            }
+        },
+        None => ("text/plain", config.top_level.unknown_site_body().as_bytes().to_vec()),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status} {}\r\nConnection: close\r\nContent-Type: {content_type}; charset=utf-8\r\nContent-Length: {}\r\n\r\n",
+        reason_phrase(status),
+        content.len()
+    );
+    let mut response = head.into_bytes();
+    response.extend_from_slice(&content);
+    write_response(stream, &response).await;
+}
+
+/// Answers that the site is starting, with the landing page for browsers and a JSON body for other clients
+async fn serve_unavailable(stream: TcpStream, controller: &SiteController, config: &Config, is_browser: bool) {
+    let (done, duration) = controller.get_progress().await.unwrap_or_default();
+    if !is_browser {
+        landing::serve_starting_json(stream, done, duration).await;
+        return;
+    }
+
+    let landing_folder = controller.config.landing_folder(config);
+    landing::serve_landing_page(
+        stream,
+        landing_folder,
+        &controller.config.name,
+        done,
+        duration,
+        controller.config.keep_alive,
+        progress_url(config, &controller.config.name).as_deref(),
+    ).await;
+}
+
+/// Proxies a request to a site that is waking up, retrying until the upstream answers.
+/// While the site isn't serving, `wake_concurrency` limits the health probes made before each attempt, and not the attempts themselves.
+async fn proxy_once_up(controller: &SiteController, head: Vec<String>, body: Vec<u8>, real_ip: Option<&str>) -> Vec<u8> {
+    loop {
+        let permit = match controller.is_serving() {
+            true => None,
+            false => controller.acquire_wake_permit().await,
+        };
+        if let Some(permit) = permit {
+            let healthy = is_healthy(controller.config).await;
+            drop(permit);
+            if !healthy {
+                sleep(Duration::from_millis(controller.config.proxy_check_interval_ms.0)).await;
+                continue;
             }
         }
-        return false;
+
+        if let Ok(response) = try_proxy(controller.config, head.clone(), body.clone(), real_ip).await {
+            debug!("Site {} is ready, got response", controller.config.name);
+            return response;
+        }
+        sleep(Duration::from_millis(controller.config.proxy_check_interval_ms.0)).await;
     }
+}
 
-    true
+/// Applies `proxy_set_headers` to a request head, replacing the headers it already has and appending the others.
+/// `$client` and `$host` in values are replaced by the client IP and the original Host header, and headers with empty values are skipped.
+fn set_proxy_headers(site_config: &SiteConfig, head: &mut Vec<String>, real_ip: Option<&str>) {
+    let host = header_value(head, "host").unwrap_or_default().to_string();
+    for (name, value) in &site_config.proxy_set_headers {
+        let value = value.replace("$client", real_ip.unwrap_or_default()).replace("$host", &host);
+        head.retain(|line| !line.split_once(':').is_some_and(|(header, _)| header.trim().eq_ignore_ascii_case(name)));
+        if !value.is_empty() {
+            head.push(format!("{name}: {value}"));
+        }
+    }
 }
 
-async fn try_proxy(port: u16, head: Vec<String>, body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-    let mut upstream = TcpStream::connect(format!("127.0.0.1:{port}")).await?;
+/// Sends a request to the service and returns its raw response, which must be relayed as is.
+/// Compressed or chunked bodies are never decoded, so that they reach the client byte for byte.
+async fn try_proxy(site_config: &SiteConfig, mut head: Vec<String>, body: Vec<u8>, real_ip: Option<&str>) -> anyhow::Result<Vec<u8>> {
+    set_proxy_headers(site_config, &mut head, real_ip);
+
+    if let Some(host) = &site_config.upstream_host_header {
+        for line in head.iter_mut().skip(1) {
+            if line.to_lowercase().starts_with("host:") {
+                *line = format!("Host: {host}");
+            }
+        }
+    }
+
+    let mut upstream = UpstreamStream::connect(site_config).await?;
 
     upstream.write_all(head.join("\r\n").as_bytes()).await?;
     upstream.write_all(b"\r\n\r\n").await?;
@@ -158,12 +402,42 @@ async fn try_proxy(port: u16, head: Vec<String>, body: Vec<u8>) -> anyhow::Resul
     Ok(response)
 }
 
+/// Parses the status code from the status line of an HTTP response
+fn response_status(response: &[u8]) -> Option<u16> {
+    let end = response.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(response.len());
+    let status_line = std::str::from_utf8(&response[..end]).ok()?;
+    status_line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Parses the Content-Length header, or returns `None` if it isn't a number.
+/// Lengths that don't even fit in a u64 are reported as [`u64::MAX`].
+fn content_length(http_request: &[String]) -> Option<u64> {
+    let Some(line) = http_request.iter().find(|line| line.to_lowercase().starts_with("content-length: ")) else {
+        return Some(0);
+    };
+    let value = line[16..].trim();
+    match value.parse::<u64>() {
+        Ok(content_length) => Some(content_length),
+        Err(_) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => Some(u64::MAX),
+        Err(_) => None,
+    }
+}
+
+// It's ok to panic in this function, as it's only called in its own thread
+/// Reads the body from the reader the head was read from, as it may have buffered part of the body
+async fn read_body(reader: &mut (impl AsyncRead + Unpin), content_length: u64) -> Vec<u8> {
+    let mut body = vec![0; content_length as usize];
+    reader.read_exact(&mut body).await.expect("Could not read request body");
+    body
+}
+
 // It's ok to panic in this function, as it's only called in its own thread
-async fn handle_connection(mut stream: TcpStream, config: &'static Config) -> ConnectionMetadata {
+async fn handle_connection(stream: TcpStream, config: &'static Config) -> ConnectionMetadata {
     use ConnectionResult::*;
 
-    let buf_reader = BufReader::new(&mut stream);
-    let http_request: Vec<_> = LinesStream::new(buf_reader.lines())
+    // The reader is kept until the body is read, as it may have buffered part of it
+    let mut reader = BufReader::new(stream);
+    let http_request: Vec<_> = LinesStream::new((&mut reader).lines())
         .map(|result| result.expect("Could not read request lines"))
         .take_while(|line| !line.is_empty())
         .collect()
@@ -171,17 +445,38 @@ async fn handle_connection(mut stream: TcpStream, config: &'static Config) -> Co
 
     // Extract metadata early
     let is_browser = http_request.iter().any(|line| line.to_lowercase() == "sec-fetch-mode: navigate");
-    let real_ip = http_request
-        .iter()
-        .find(|line| line.to_lowercase().starts_with("x-real-ip: "))
-        .map(|line| line[11..].to_string());
+    let real_ip = extract_real_ip(&http_request, config);
+
+    let Some(content_length) = content_length(&http_request) else {
+        debug!("Client sent an invalid Content-Length");
+        let status_line = "HTTP/1.1 400 Bad Request";
+        let content = "Invalid Content-Length";
+        let length = content.len();
+        let response = format!("{status_line}\r\nConnection: close\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {length}\r\n\r\n{content}");
+        write_response(reader.get_mut(), response.as_bytes()).await;
+        return ConnectionMetadata::new(http_request, InvalidContentLength, is_browser, real_ip);
+    };
+
+    // Refuse bodies that would take too much memory before allocating them
+    if content_length > config.top_level.max_request_body_bytes() {
+        debug!("Client sent a body of {content_length} bytes, more than max_request_body_bytes");
+        let status_line = "HTTP/1.1 413 Payload Too Large";
+        let content = "Request body is too large";
+        let length = content.len();
+        let response = format!("{status_line}\r\nConnection: close\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {length}\r\n\r\n{content}");
+        write_response(reader.get_mut(), response.as_bytes()).await;
+        return ConnectionMetadata::new(http_request, BodyTooLarge, is_browser, real_ip);
+    }
 
     let first_line = http_request.first().expect("Request is empty");
     let path = first_line.split_whitespace().nth(1).expect("Request line is empty");
 
-    if path.starts_with("/hibernator-api/") {
+    let api_path = path
+        .strip_prefix(config.top_level.api_prefix())
+        .filter(|api_path| api_path.starts_with('/') && config.top_level.api_enabled());
+    if let Some(api_path) = api_path {
         // Handle hibernator API requests with authentication
-        if handle_api_request(stream, &http_request, path, config).await {
+        if handle_api_request(reader.into_inner(), &http_request, api_path, config).await {
             return ConnectionMetadata::api_handled();
         }
         // If handle_api_request returns false, it means no endpoint matched
@@ -199,33 +494,70 @@ async fn handle_connection(mut stream: TcpStream, config: &'static Config) -> Co
         Some(host) => host,
         None => {
             debug!("Client didn't provide a Host header");
-            let status_line = "HTTP/1.1 500 Internal Server Error";
-            let content = "Hibernator requires a Host header";
-            let length = content.len();
-            let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{content}");
-            let _ = stream.write_all(response.as_bytes()).await;
+            write_unknown_site_response(reader.get_mut(), config).await;
             return ConnectionMetadata::new(http_request, MissingHost, is_browser, real_ip);
         }
     };
 
-    let controller = get_controller(&host);
+    let controller = get_controller(&host, path);
     let controller = match controller {
         Some(controller) => controller,
         None => {
             debug!("Client requested a site that doesn't exist (host: {host})");
-            let status_line = "HTTP/1.1 500 Internal Server Error";
-            let content = format!("Hibernator doesn't know about the site you're trying to access (host: {host})");
-            let length = content.len();
-            let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{content}");
-            let _ = stream.write_all(response.as_bytes()).await;
+            write_unknown_site_response(reader.get_mut(), config).await;
             return ConnectionMetadata::new(http_request, UnknownSite, is_browser, real_ip);
         }
     };
 
+    // Disabled sites aren't managed, so requests are passed through to them as they are
+    if !controller.config.enabled() {
+        let body = read_body(&mut reader, content_length).await;
+        let mut stream = reader.into_inner();
+        let timeout_duration = Duration::from_millis(controller.config.proxy_timeout_ms(is_browser));
+        if let Ok(Ok(response)) = timeout(timeout_duration, try_proxy(controller.config, http_request.clone(), body, real_ip.as_deref())).await {
+            write_response(&mut stream, &response).await;
+            return ConnectionMetadata::new(http_request, ProxySuccess, is_browser, real_ip).with_controller(controller).with_status(response_status(&response));
+        }
+
+        debug!("Disabled site {} didn't answer", controller.config.name);
+        let status_line = "HTTP/1.1 502 Bad Gateway";
+        let content = "Site is not managed by the hibernator and did not answer";
+        let length = content.len();
+        let response = format!("{status_line}\r\nConnection: close\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {length}\r\n\r\n{content}");
+        write_response(&mut stream, response.as_bytes()).await;
+        return ConnectionMetadata::new(http_request, ProxyFailed, is_browser, real_ip).with_controller(controller);
+    }
+
+    let cold = !controller.is_serving();
+
     // Make sure the request should be treated
     let first_line = http_request.first().expect("Request is empty");
     let path = first_line.split_whitespace().nth(1).expect("Request line is empty");
     if !should_be_processed(controller.config, path, real_ip.as_deref()) {
+        // nginx might not be switched back to the site yet, in which case it would have served the request itself
+        if controller.is_switching_to_up() {
+            let body = read_body(&mut reader, content_length).await;
+            if let Ok(response) = try_proxy(controller.config, http_request.clone(), body, real_ip.as_deref()).await {
+                debug!("Proxied ignored request to {} as it is up", controller.config.name);
+                write_response(reader.get_mut(), &response).await;
+                return ConnectionMetadata::new(http_request, ProxySuccess, is_browser, real_ip).with_controller(controller).with_cold(cold).with_status(response_status(&response));
+            }
+        }
+        let mut stream = reader.into_inner();
+
+        // Blacklisted requests often come right before real ones, so the site can start warming up
+        if !controller.is_serving() && should_warm(controller.config, path, real_ip.as_deref()) {
+            debug!("Warming up {} on a blacklisted path", controller.config.name);
+            controller.trigger_start(TriggerSource::Request);
+        }
+
+        // Requests outside the wake paths get told the site is asleep, like any request would
+        if !controller.config.is_wake_path(path) && !controller.config.is_path_blacklisted(path) {
+            debug!("Path {path} can't wake {} up", controller.config.name);
+            serve_unavailable(stream, controller, config, is_browser).await;
+            return ConnectionMetadata::new(http_request, Ignored, is_browser, real_ip).with_controller(controller).with_cold(cold);
+        }
+
         debug!("Client shall not be served");
         let status_line = "HTTP/1.1 503 Service Unavailable";
         let retry_after = controller.get_progress().await.and_then(|(done, duration)| {
@@ -234,9 +566,9 @@ async fn handle_connection(mut stream: TcpStream, config: &'static Config) -> Co
         }).unwrap_or_default();
         let content = "Server is unavailable";
         let length = content.len();
-        let response = format!("{status_line}\r\nContent-Length: {length}\r\n{retry_after}\r\n{content}");
-        let _ = stream.write_all(response.as_bytes()).await;
-        return ConnectionMetadata::new(http_request, Ignored, is_browser, real_ip).with_controller(controller);
+        let response = format!("{status_line}\r\nConnection: close\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {length}\r\n{retry_after}\r\n{content}");
+        write_response(&mut stream, response.as_bytes()).await;
+        return ConnectionMetadata::new(http_request, Ignored, is_browser, real_ip).with_controller(controller).with_cold(cold);
     }
 
     // Determine if we should attempt to proxy the request
@@ -246,73 +578,352 @@ async fn handle_connection(mut stream: TcpStream, config: &'static Config) -> Co
     };
     let should_proxy = match proxy_mode {
         ProxyMode::Always => true,
-        ProxyMode::WhenReady => controller.get_state().is_up(),
+        ProxyMode::WhenReady | ProxyMode::WhenStarting => controller.is_serving(),
         ProxyMode::Never => false,
     };
     debug!("Is browser: {is_browser}, Proxy mode: {proxy_mode:?}, Should proxy: {should_proxy}");
 
-    if !should_proxy {
-        debug!("Returning 503 right away");
-        let (done, duration) = controller.get_progress().await.unwrap_or_default();
-        let landing_folder = controller.config.landing_folder(config);
-        landing::serve_landing_page(
-            stream,
-            landing_folder,
-            done,
-            duration,
-            controller.config.keep_alive,
-        ).await;
+    // The site might be ready any moment now, so give it a quick chance
+    let quick_attempt = !should_proxy && *proxy_mode == ProxyMode::WhenStarting && controller.get_state() == SiteState::Starting;
 
-        controller.trigger_start();
+    // A body would be lost with the landing page, so hold the request until the site is up and replay it then
+    let replay_body = !should_proxy
+        && *proxy_mode != ProxyMode::Never
+        && content_length > 0
+        && controller.config.replay_body_max_bytes.is_some_and(|max_bytes| content_length <= max_bytes as u64);
 
-        return ConnectionMetadata::new(http_request, Unproxied, is_browser, real_ip.clone()).with_controller(controller);
+    // Requests answered right away don't need their body
+    let body = match should_proxy || replay_body || quick_attempt {
+        true => read_body(&mut reader, content_length).await,
+        false => Vec::new(),
+    };
+    let mut stream = reader.into_inner();
+
+    if quick_attempt {
+        let quick_timeout = Duration::from_millis(STARTING_PROXY_TIMEOUT_MS);
+        if let Ok(Ok(response)) = timeout(quick_timeout, try_proxy(controller.config, http_request.clone(), body.clone(), real_ip.as_deref())).await {
+            debug!("Site {} answered while starting", controller.config.name);
+            write_response(&mut stream, &response).await;
+            return ConnectionMetadata::new(http_request, ProxySuccess, is_browser, real_ip).with_controller(controller).with_cold(cold).with_status(response_status(&response));
+        }
     }
 
-    let content_length = http_request
-        .iter()
-        .find(|line| line.to_lowercase().starts_with("content-length: "))
-        .map(|line| line[16..].parse::<usize>().expect("Could not parse content length"))
-        .unwrap_or(0);
-    let mut body = vec![0; content_length];
-    stream.read_exact(&mut body).await.expect("Could not read request body");
+    if !should_proxy && !replay_body {
+        debug!("Returning 503 right away");
+        serve_unavailable(stream, controller, config, is_browser).await;
+
+        controller.trigger_start(TriggerSource::Request);
+
+        return ConnectionMetadata::new(http_request, Unproxied, is_browser, real_ip.clone()).with_controller(controller).with_cold(cold);
+    }
 
-    let timeout_duration = Duration::from_millis(controller.config.proxy_timeout_ms.0);
+    let timeout_duration = Duration::from_millis(controller.config.proxy_timeout_ms(is_browser));
     let http_request2 = http_request.clone();
+    let proxy_started = Instant::now();
+    let wake_wait = OnceLock::new();
+    let wake_wait_ref = &wake_wait;
+    let real_ip_ref = real_ip.as_deref();
     let r = timeout(timeout_duration, async move {
-        controller.waiting_trigger_start().await;
+        controller.waiting_trigger_start(TriggerSource::Request).await?;
+        let _ = wake_wait_ref.set(proxy_started.elapsed());
         debug!("Site started, waiting for upstream");
-        loop {
-            if let Ok(response) = try_proxy(controller.config.port, http_request2.clone(), body.clone()).await {
-                debug!("Site {} is ready, got response", controller.config.name);
-                return Ok::<Vec<u8>, anyhow::Error>(response);
-            }
-            sleep(Duration::from_millis(controller.config.proxy_check_interval_ms.0)).await;
-        }
+        Ok::<Vec<u8>, anyhow::Error>(proxy_once_up(controller, http_request2, body, real_ip_ref).await)
     }).await;
 
-    match r {
+    // A request that timed out before the site started spent all its time waiting for it
+    let total = proxy_started.elapsed();
+    let upstream = wake_wait.get().map(|wake_wait| total.saturating_sub(*wake_wait));
+    let wake_wait = wake_wait.get().copied().unwrap_or(total);
+
+    let metadata = match r {
         Ok(Ok(response)) => {
             debug!("Returning response from upstream");
-            let _ = stream.write_all(&response).await;
-            ConnectionMetadata::new(http_request, ProxySuccess, is_browser, real_ip).with_controller(controller)
+            write_response(&mut stream, &response).await;
+            ConnectionMetadata::new(http_request, ProxySuccess, is_browser, real_ip).with_controller(controller).with_cold(cold).with_status(response_status(&response))
+        },
+        Ok(Err(e)) if is_browser => {
+            // Browsers get the landing page, which reloads automatically
+            debug!("Error while proxying to {}, serving landing page: {e}", controller.config.name);
+            serve_unavailable(stream, controller, config, is_browser).await;
+            ConnectionMetadata::new(http_request, ProxyFailed, is_browser, real_ip).with_controller(controller).with_cold(cold)
         },
         Ok(Err(e)) => {
-            let status_line = "HTTP/1.1 500 Internal Server Error";
+            let status_line = "HTTP/1.1 502 Bad Gateway";
             let content = format!("Error while starting site: {e}");
             let length = content.len();
-            let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{content}");
-            let _ = stream.write_all(response.as_bytes()).await;
-            ConnectionMetadata::new(http_request, ProxyFailed, is_browser, real_ip.clone()).with_controller(controller)
+            let response = format!("{status_line}\r\nConnection: close\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {length}\r\n\r\n{content}");
+            write_response(&mut stream, response.as_bytes()).await;
+            ConnectionMetadata::new(http_request, ProxyFailed, is_browser, real_ip.clone()).with_controller(controller).with_cold(cold)
+        },
+        Err(_) if is_browser => {
+            // Browsers get the auto-refreshing landing page, like when they aren't proxied
+            debug!("Site {} took too long to start, serving landing page", controller.config.name);
+            serve_unavailable(stream, controller, config, is_browser).await;
+            ConnectionMetadata::new(http_request, ProxyTimeout, is_browser, real_ip).with_controller(controller).with_cold(cold)
         },
         Err(_) => {
             debug!("Site {} took too long to start", controller.config.name);
 
             let status_line = "HTTP/1.1 504 Gateway Timeout";
-            let content = "Site is booting up. Try again.";
+            let (content, retry_after) = match controller.get_progress().await {
+                Some((done, duration)) => {
+                    let remaining = duration.saturating_sub(done).as_secs().max(1);
+                    let percent = (done.as_millis() * 100 / duration.as_millis().max(1)).min(99);
+                    (format!("Site is booting up ({percent}%). Try again in {remaining}s."), format!("Retry-After: {remaining}\r\n"))
+                },
+                None => (String::from("Site is booting up. Try again."), String::new()),
+            };
             let length = content.len();
-            let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{content}");
-            let _ = stream.write_all(response.as_bytes()).await;
-            ConnectionMetadata::new(http_request, ProxyTimeout, is_browser, real_ip).with_controller(controller)
+            let response = format!("{status_line}\r\nConnection: close\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {length}\r\n{retry_after}\r\n{content}");
+            write_response(&mut stream, response.as_bytes()).await;
+            ConnectionMetadata::new(http_request, ProxyTimeout, is_browser, real_ip).with_controller(controller).with_cold(cold)
         },
+    };
+
+    metadata.with_timings(wake_wait, upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::test_utils::*;
+    use super::*;
+
+    fn test_site_config(extra: &str) -> SiteConfig {
+        toml::from_str(&format!("name = \"site\"\nhosts = [\"site.test\"]\nservice_name = \"site\"\naccess_log = \"/dev/null\"\nkeep_alive = 60\n{extra}")).unwrap()
+    }
+
+    fn test_metadata(request: &[&str]) -> ConnectionMetadata {
+        ConnectionMetadata::new(request.iter().map(|line| line.to_string()).collect(), ConnectionResult::ProxySuccess, false, None)
+    }
+
+    #[test]
+    fn trim_request_cuts_lines_on_character_boundaries() {
+        let config: Config = toml::from_str("").unwrap();
+        let site_config = test_site_config("history_max_line_bytes = 9");
+        let mut metadata = test_metadata(&["GET / HTTP/1.1", "X-Name: éé", "X-Real-IP: 1.2.3.4", "Host: x.test", "", "body"]);
+        metadata.trim_request(&config, Some(&site_config));
+        assert_eq!(metadata.request, ["X-Name: ", "Host: x.t"]);
+    }
+
+    #[test]
+    fn trim_request_keeps_verbatim_headers_up_to_the_byte_cap() {
+        let config: Config = toml::from_str("").unwrap();
+
+        let site_config = test_site_config("history_verbatim_headers = true");
+        let mut metadata = test_metadata(&["GET / HTTP/1.1", "X-Real-IP: 1.2.3.4", "X-Name: éé", "", "body"]);
+        metadata.trim_request(&config, Some(&site_config));
+        assert_eq!(metadata.request, ["GET / HTTP/1.1", "X-Real-IP: 1.2.3.4", "X-Name: éé"]);
+
+        // The cap falls in the middle of the first é
+        let site_config = test_site_config("history_verbatim_headers = true\nhistory_verbatim_max_bytes = 23");
+        let mut metadata = test_metadata(&["GET / HTTP/1.1", "X-Name: éé", "Accept: */*"]);
+        metadata.trim_request(&config, Some(&site_config));
+        assert_eq!(metadata.request, ["GET / HTTP/1.1", "X-Name: ", "A"]);
+    }
+
+    #[tokio::test]
+    async fn proxies_while_nginx_is_switched_back() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let reloaded = env.path("reloaded");
+        let slow_reload = env.script("slow-reload", &format!("sleep 1\ntouch {reloaded}"));
+        let (upstream_port, _) = upstream(ok_response("upstream")).await;
+        let config = env.load("", vec![env.site("transition", upstream_port, &format!("nginx_reload_command = \"{slow_reload}\""))]);
+        let port = start_hibernator(config).await;
+
+        // The first check finds the site up and starts switching nginx back to it
+        let controller = get_controller_by_name("transition").unwrap();
+        timeout(TEST_TIMEOUT, async {
+            while !controller.get_state().is_up() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+
+        let response = get(port, "transition.test", "/", &["Sec-Fetch-Mode: navigate"]).await;
+        assert!(!Path::new(&reloaded).exists(), "nginx was reloaded before the request was answered");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "upstream");
+    }
+
+    #[tokio::test]
+    async fn failed_starts_serve_the_landing_page_to_browsers_only() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let failing_systemctl = env.script("failing-systemctl", "exit 1");
+        let site = env.site("failing", free_port(), &format!("systemctl_prefix = \"{failing_systemctl}\"\nbrowser_proxy_mode = \"Always\""));
+        let port = start_hibernator(env.load("", vec![site])).await;
+
+        let response = get(port, "failing.test", "/", &["Sec-Fetch-Mode: navigate"]).await;
+        assert_eq!(response.status, 503);
+        assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+        assert!(response.text().contains("<h1>failing</h1>"), "{}", response.text());
+
+        let response = get(port, "failing.test", "/", &[]).await;
+        assert_eq!(response.status, 502);
+        assert!(response.text().starts_with("Error while starting site"), "{}", response.text());
+    }
+
+    #[tokio::test]
+    async fn upstream_host_header_replaces_the_host() {
+        let (upstream_port, mut heads) = upstream(ok_response("ok")).await;
+        let site_config = test_site_config(&format!("port = {upstream_port}\nupstream_host_header = \"internal.example\""));
+        let head = vec![String::from("GET / HTTP/1.1"), String::from("Host: public.example")];
+
+        try_proxy(&site_config, head, Vec::new(), None).await.unwrap();
+        let head = heads.recv().await.unwrap();
+        assert!(head.contains("\r\nHost: internal.example\r\n"), "{head}");
+        assert!(!head.contains("public.example"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn only_whitelisted_paths_wake_the_site() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let site = env.site("whitelisted", free_port(), "wake_path_whitelist = [\"/app/*\"]");
+        let port = start_hibernator(env.load("", vec![site])).await;
+
+        let response = get(port, "whitelisted.test", "/other", &[]).await;
+        assert_eq!(response.status, 503);
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        let response = get(port, "whitelisted.test", "/other", &["Sec-Fetch-Mode: navigate"]).await;
+        assert_eq!(response.status, 503);
+        assert!(response.text().contains("<h1>whitelisted</h1>"), "{}", response.text());
+        sleep(Duration::from_millis(200)).await;
+        assert!(!env.commands().iter().any(|command| command.contains("start")), "{:?}", env.commands());
+
+        // The site never comes up, so the request is left waiting
+        spawn(send_raw(port, b"GET /app/page HTTP/1.1\r\nHost: whitelisted.test\r\n\r\n"));
+        env.wait_for_command("start whitelisted.service").await;
+    }
+
+    #[tokio::test]
+    async fn simultaneous_cold_requests_start_the_site_once() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let upstream_port = free_port();
+        let site = env.site("stress", upstream_port, "proxy_mode = \"Always\"");
+        let port = start_hibernator(env.load("", vec![site])).await;
+
+        let requests: Vec<_> = (0..50).map(|_| spawn(get(port, "stress.test", "/", &[]))).collect();
+        env.wait_for_command("start stress.service").await;
+        serve_upstream(TcpListener::bind(("127.0.0.1", upstream_port)).await.unwrap(), ok_response("up"));
+
+        for request in requests {
+            let response = request.await.unwrap();
+            assert_eq!(response.status, 200);
+            assert_eq!(response.text(), "up");
+        }
+        let starts = env.commands().iter().filter(|command| command.ends_with(" start stress.service")).count();
+        assert_eq!(starts, 1, "{:?}", env.commands());
+    }
+
+    #[tokio::test]
+    async fn wake_permits_are_released_before_proxying() {
+        let env = TestEnv::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let site = env.site("permits", listener.local_addr().unwrap().port(), "wake_concurrency = 1");
+        let config = env.load("", vec![site]);
+        let (controller, _, _) = SiteController::new(&config.sites[0]).await;
+        DATABASE.update_state("permits", SiteState::Starting).unwrap();
+
+        // The upstream only answers once both requests reached it
+        let barrier = std::sync::Arc::new(tokio::sync::Barrier::new(2));
+        spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let barrier = barrier.clone();
+                spawn(async move {
+                    if read_head(&mut stream).await.is_some() {
+                        barrier.wait().await;
+                        let _ = stream.write_all(ok_response("both").as_bytes()).await;
+                    }
+                });
+            }
+        });
+
+        let head = vec![String::from("GET / HTTP/1.1"), String::from("Host: permits.test")];
+        let responses = timeout(TEST_TIMEOUT, async {
+            tokio::join!(proxy_once_up(&controller, head.clone(), Vec::new(), None), proxy_once_up(&controller, head.clone(), Vec::new(), None))
+        }).await.expect("requests held each other back");
+        assert_eq!(Response::parse(&responses.0).text(), "both");
+        assert_eq!(Response::parse(&responses.1).text(), "both");
+    }
+
+    #[tokio::test]
+    async fn bodies_are_only_read_when_proxying() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let port = start_hibernator(env.load("", vec![env.site("unread", free_port(), "proxy_mode = \"WhenReady\"")])).await;
+
+        // The announced body is never sent, so reading it would hang
+        let response = Response::parse(&send_raw(port, b"POST / HTTP/1.1\r\nHost: unknown.test\r\nContent-Length: 10\r\n\r\n").await);
+        assert_eq!(response.status, 404);
+        let response = Response::parse(&send_raw(port, b"POST / HTTP/1.1\r\nHost: unread.test\r\nContent-Length: 10\r\n\r\n").await);
+        assert_eq!(response.status, 503);
+    }
+
+    #[test]
+    fn content_length_is_parsed_or_rejected() {
+        let head = |line: &str| vec![String::from("POST / HTTP/1.1"), String::from(line)];
+        assert_eq!(content_length(&head("Host: site.test")), Some(0));
+        assert_eq!(content_length(&head("Content-Length: 42")), Some(42));
+        assert_eq!(content_length(&head("Content-Length: 99999999999999999999999")), Some(u64::MAX));
+        assert_eq!(content_length(&head("Content-Length: forty")), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_content_lengths_are_bad_requests() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let port = start_hibernator(env.load("", vec![env.site("length", free_port(), "")])).await;
+
+        let response = Response::parse(&send_raw(port, b"POST / HTTP/1.1\r\nHost: length.test\r\nContent-Length: forty\r\n\r\n").await);
+        assert_eq!(response.status, 400);
+        assert_eq!(response.text(), "Invalid Content-Length");
+    }
+
+    #[tokio::test]
+    async fn compressed_responses_pass_through_unchanged() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"compressed body").unwrap();
+        let body = encoder.finish().unwrap();
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+        response.extend_from_slice(&body);
+
+        let (upstream_port, _) = upstream(response.clone()).await;
+        let site_config = test_site_config(&format!("port = {upstream_port}"));
+        let head = vec![String::from("GET / HTTP/1.1"), String::from("Host: site.test"), String::from("Accept-Encoding: gzip")];
+        assert_eq!(try_proxy(&site_config, head, Vec::new(), None).await.unwrap(), response);
+    }
+
+    #[tokio::test]
+    async fn ignored_requests_are_only_proxied_while_nginx_is_switched_back() {
+        let _globals = lock_globals().await;
+        let env = TestEnv::new();
+        let reloaded = env.path("reloaded");
+        let slow_reload = env.script("slow-reload", &format!("sleep 1\ntouch {reloaded}"));
+        let (upstream_port, _) = upstream(ok_response("upstream")).await;
+        let site = env.site("blacklisted-ip", upstream_port, &format!("nginx_reload_command = \"{slow_reload}\"\nip_blacklist = [\"192.0.2.1\"]"));
+        let port = start_hibernator(env.load("", vec![site])).await;
+
+        let controller = get_controller_by_name("blacklisted-ip").unwrap();
+        timeout(TEST_TIMEOUT, async {
+            while !controller.get_state().is_up() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        let response = get(port, "blacklisted-ip.test", "/", &["X-Real-IP: 192.0.2.1"]).await;
+        assert!(!Path::new(&reloaded).exists(), "nginx was reloaded before the request was answered");
+        assert_eq!(response.status, 200);
+
+        timeout(TEST_TIMEOUT, async {
+            while controller.is_switching_to_up() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        let response = get(port, "blacklisted-ip.test", "/", &["X-Real-IP: 192.0.2.1"]).await;
+        assert_eq!(response.status, 503);
     }
 }
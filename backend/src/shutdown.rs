@@ -0,0 +1,44 @@
+//! Graceful shutdown: stop accepting connections, let the active ones finish, and record a final state for each site.
+
+use std::{sync::{atomic::{AtomicUsize, Ordering}, LazyLock}, time::Duration};
+use tokio::{sync::watch, time::{sleep, timeout}};
+
+static SHUTDOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn request_shutdown() {
+    SHUTDOWN.send_replace(true);
+}
+
+/// Resolves once a shutdown has been requested.
+pub async fn shutdown_requested() {
+    let mut receiver = SHUTDOWN.subscribe();
+    let _ = receiver.wait_for(|shutdown| *shutdown).await;
+}
+
+/// Counts a connection as active for as long as it's alive.
+pub struct ConnectionGuard;
+
+impl ConnectionGuard {
+    pub fn acquire() -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Waits for active connections to finish, up to `max_wait`.
+/// Returns the number of connections still active.
+pub async fn wait_for_connections(max_wait: Duration) -> usize {
+    let _ = timeout(max_wait, async {
+        while ACTIVE_CONNECTIONS.load(Ordering::Relaxed) > 0 {
+            sleep(Duration::from_millis(100)).await;
+        }
+    }).await;
+    ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,81 @@
+//! The `status` subcommand, printing the state of the sites of a running hibernator.
+
+use std::{net::IpAddr, time::Duration};
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use url::Url;
+use crate::{api::{HistoryEntry, ServiceInfo}, Config};
+
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Environment variable holding the API key, since only its hash is in the config
+const API_KEY_VAR: &str = "HIBERNATOR_API_KEY";
+
+/// Whether the `status` subcommand was requested
+pub fn status_mode() -> bool {
+    std::env::args().nth(1).is_some_and(|arg| arg == "status")
+}
+
+/// Formats the time elapsed since a date, like `5m ago`
+fn format_ago(since: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (now - since).num_seconds().max(0);
+    match seconds {
+        0..60 => format!("{seconds}s ago"),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+async fn get<T: serde::de::DeserializeOwned>(client: &reqwest::Client, config: &Config, url: &str) -> anyhow::Result<T> {
+    let mut request = client.get(url);
+    if let Some(token) = &config.top_level.api_token {
+        request = request.header("Authorization", format!("Bearer {token}"));
+    } else if let Ok(api_key) = std::env::var(API_KEY_VAR) {
+        request = request.header("X-Api-Key", api_key);
+    }
+
+    let response = request.send().await.map_err(|e| anyhow!("could not reach the hibernator at {url}: {e}"))?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(anyhow!("the hibernator answered {status}: {body}"));
+    }
+
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// Prints a table of the sites of the hibernator running with this config
+pub async fn print_status(config: &Config) -> anyhow::Result<()> {
+    if !config.top_level.api_enabled() {
+        return Err(anyhow!("the status subcommand requires the API, which is disabled"));
+    }
+
+    // A hibernator listening on every interface can be reached locally
+    let host = match config.top_level.bind_address().parse::<IpAddr>() {
+        Ok(address) if address.is_unspecified() => String::from("127.0.0.1"),
+        Ok(IpAddr::V6(address)) => format!("[{address}]"),
+        _ => config.top_level.bind_address().to_string(),
+    };
+    let base_url = format!("http://{host}:{}{}", config.top_level.hibernator_port(), config.top_level.api_prefix());
+
+    let client = reqwest::Client::builder().timeout(STATUS_TIMEOUT).build()?;
+    let services: Vec<ServiceInfo> = get(&client, config, &format!("{base_url}/services")).await?;
+
+    let now = Utc::now();
+    let name_width = services.iter().map(|service| service.name.len()).max().unwrap_or(0).max(4);
+    println!("{:name_width$}  {:9}  {:10}  LAST REQUEST", "SITE", "STATE", "SINCE");
+    for service in services {
+        let history_url = Url::parse_with_params(&format!("{base_url}/history"), [("service", service.name.as_str()), ("minResults", "1")])?;
+        let history: Vec<HistoryEntry> = get(&client, config, history_url.as_str()).await?;
+        let last_request = history
+            .first()
+            .and_then(|entry| DateTime::from_timestamp(entry.timestamp as i64, 0))
+            .map(|timestamp| format_ago(timestamp, now))
+            .unwrap_or_else(|| String::from("-"));
+
+        println!("{:name_width$}  {:9}  {:10}  {last_request}", service.name, service.state, format_ago(service.last_changed, now));
+    }
+
+    Ok(())
+}
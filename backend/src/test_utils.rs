@@ -0,0 +1,275 @@
+//! Helpers shared by the tests.
+//! Sites are served by real sockets, with scripts standing in for systemctl and nginx, and the database lives in a temporary directory.
+
+use std::{fs, os::unix::fs::{symlink, PermissionsExt}, path::PathBuf, sync::{Mutex as StdMutex, OnceLock}, time::Duration};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, spawn, sync::{mpsc::{unbounded_channel, UnboundedReceiver}, Mutex, MutexGuard}, time::{sleep, timeout}};
+use crate::{config::{load_config, set_current_config, ConfigSource}, reload::start_controllers, server::setup_server, Config};
+
+/// How long tests wait for something that should happen quickly
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+static GLOBALS: Mutex<()> = Mutex::const_new(());
+
+/// Serializes the tests using the current config or the site controllers, as they are global
+pub async fn lock_globals() -> MutexGuard<'static, ()> {
+    GLOBALS.lock().await
+}
+
+/// Keeps log messages so that tests can check what was logged
+struct CapturingLogger(StdMutex<Vec<String>>);
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().expect("log lock poisoned").push(format!("{} {}", record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: OnceLock<&'static CapturingLogger> = OnceLock::new();
+
+/// Messages logged so far by all tests, prefixed with their level
+pub fn logs() -> Vec<String> {
+    let logger = LOGGER.get_or_init(|| {
+        let logger: &'static CapturingLogger = Box::leak(Box::new(CapturingLogger(StdMutex::new(Vec::new()))));
+        let _ = log::set_logger(logger);
+        log::set_max_level(LevelFilter::Info);
+        logger
+    });
+    logger.0.lock().expect("log lock poisoned").clone()
+}
+
+fn write_script(path: &PathBuf, content: &str) {
+    fs::write(path, content).expect("could not write script");
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).expect("could not make script executable");
+}
+
+/// A temporary directory holding the config and every file sites need.
+/// The fake systemctl and nginx record their arguments in the `commands` file.
+pub struct TestEnv {
+    pub dir: PathBuf,
+}
+
+impl TestEnv {
+    pub fn new() -> Self {
+        logs();
+        let dir = tempfile::tempdir().expect("could not create test directory").keep();
+
+        fs::create_dir(dir.join("landing")).expect("could not create landing folder");
+        fs::write(dir.join("landing/index.html"), "<h1>SITE_NAME</h1><p>KEEP_ALIVE DONE_MS DURATION_MS PROGRESS_URL</p>").expect("could not write landing page");
+        fs::write(dir.join("hibernator.conf"), "").expect("could not write hibernator config");
+        let commands = dir.join("commands");
+        write_script(&dir.join("systemctl"), &format!("#!/bin/sh\necho \"systemctl $@\" >> {}\n", commands.display()));
+        write_script(&dir.join("nginx"), &format!("#!/bin/sh\necho \"nginx $@\" >> {}\n", commands.display()));
+
+        TestEnv { dir }
+    }
+
+    pub fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_str().expect("test directory isn't UTF-8").to_string()
+    }
+
+    /// Writes an executable script in the test directory and returns its path
+    pub fn script(&self, name: &str, content: &str) -> String {
+        let path = self.dir.join(name);
+        write_script(&path, &format!("#!/bin/sh\n{content}\n"));
+        self.path(name)
+    }
+
+    /// A site served on `port` for host `<name>.test`, hibernated by nginx at first.
+    /// Fields of `extra` replace the default ones.
+    pub fn site(&self, name: &str, port: u16, extra: &str) -> toml::Table {
+        let access_log = self.path(&format!("{name}.log"));
+        let available_config = self.path(&format!("{name}.conf"));
+        let enabled_config = self.path(&format!("enabled-{name}"));
+        // Sites can be written again, when a test changes the config
+        if fs::symlink_metadata(&enabled_config).is_err() {
+            fs::write(&access_log, "").expect("could not write access log");
+            fs::write(&available_config, "").expect("could not write site config");
+            symlink(self.path("hibernator.conf"), &enabled_config).expect("could not create enabled config");
+        }
+
+        let mut site: toml::Table = toml::from_str(&format!(r#"
+            name = "{name}"
+            hosts = ["{name}.test"]
+            port = {port}
+            service_name = "{name}.service"
+            keep_alive = "1h"
+            access_log = "{access_log}"
+            nginx_available_config = "{available_config}"
+            nginx_enabled_config = "{enabled_config}"
+            nginx_hibernator_config = "{}"
+            systemctl_prefix = "{}"
+            health_check_mode = "connect"
+            start_check_interval_ms = 20
+            proxy_check_interval_ms = 20
+        "#, self.path("hibernator.conf"), self.path("systemctl"))).expect("invalid site");
+        site.extend(toml::from_str::<toml::Table>(extra).expect("invalid extra site fields"));
+        site
+    }
+
+    /// Writes a config with the given sites, on a free port, and returns its path
+    pub fn write_config(&self, extra: &str, sites: Vec<toml::Table>) -> String {
+        let mut config: toml::Table = toml::from_str(&format!(r#"
+            hibernator_port = {}
+            landing_folder = "{}"
+            nginx_test_command = "{nginx} -t"
+            nginx_reload_command = "{nginx} -s reload"
+        "#, free_port(), self.path("landing"), nginx = self.path("nginx"))).expect("invalid config");
+        config.extend(toml::from_str::<toml::Table>(extra).expect("invalid extra config fields"));
+        config.insert(String::from("sites"), toml::Value::Array(sites.into_iter().map(toml::Value::Table).collect()));
+
+        let path = self.path("config.toml");
+        fs::write(&path, toml::to_string(&config).expect("could not serialize config")).expect("could not write config");
+        path
+    }
+
+    /// Writes a config like [`TestEnv::write_config`] and loads it
+    pub fn load(&self, extra: &str, sites: Vec<toml::Table>) -> &'static Config {
+        let path = self.write_config(extra, sites);
+        let config = load_config(&ConfigSource::File(path)).expect("invalid test config");
+        Box::leak(Box::new(config))
+    }
+
+    /// Commands run by the fake systemctl and nginx so far
+    pub fn commands(&self) -> Vec<String> {
+        fs::read_to_string(self.dir.join("commands")).unwrap_or_default().lines().map(String::from).collect()
+    }
+
+    /// Waits for a command containing `command` to be run
+    pub async fn wait_for_command(&self, command: &str) {
+        timeout(TEST_TIMEOUT, async {
+            while !self.commands().iter().any(|line| line.contains(command)) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap_or_else(|_| panic!("{command} was never run, only {:?}", self.commands()));
+    }
+}
+
+/// Makes the config current and runs the hibernator with it, returning the port it listens on
+pub async fn start_hibernator(config: &'static Config) -> u16 {
+    set_current_config(config);
+    start_controllers(config).await;
+    setup_server(config).await;
+    config.top_level.hibernator_port()
+}
+
+/// A port nothing listens on
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).expect("could not find a free port").port()
+}
+
+/// Reads a request head, or nothing if the connection is closed before one is sent like health checks do
+pub async fn read_head(stream: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut byte).await {
+            Ok(1) => head.push(byte[0]),
+            _ => break,
+        }
+    }
+    (!head.is_empty()).then(|| String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Answers every request made on `listener` with `response`, and sends the request heads on the returned channel
+pub fn serve_upstream(listener: TcpListener, response: impl Into<Vec<u8>>) -> UnboundedReceiver<String> {
+    let response: &'static [u8] = response.into().leak();
+    let (sender, receiver) = unbounded_channel();
+    spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let sender = sender.clone();
+            spawn(async move {
+                if let Some(head) = read_head(&mut stream).await {
+                    let _ = sender.send(head);
+                    let _ = stream.write_all(response).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    });
+    receiver
+}
+
+/// An upstream on a new port, like [`serve_upstream`]
+pub async fn upstream(response: impl Into<Vec<u8>>) -> (u16, UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("could not bind upstream");
+    let port = listener.local_addr().expect("upstream has no address").port();
+    (port, serve_upstream(listener, response))
+}
+
+/// A plain response with the given body
+pub fn ok_response(body: &str) -> String {
+    format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+}
+
+/// Sends a raw request and returns the raw response
+pub async fn send_raw(port: u16, request: &[u8]) -> Vec<u8> {
+    timeout(TEST_TIMEOUT, async {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.expect("could not connect");
+        stream.write_all(request).await.expect("could not send request");
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        response
+    }).await.expect("no response in time")
+}
+
+/// Sends a GET request to `host`, with the additional header lines given
+pub async fn get(port: u16, host: &str, path: &str, headers: &[&str]) -> Response {
+    let mut request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\n");
+    for header in headers {
+        request.push_str(header);
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    Response::parse(&send_raw(port, request.as_bytes()).await)
+}
+
+/// A response that was checked to be well-formed
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Parses a response, making sure its head is valid and its body has the advertised length
+    pub fn parse(raw: &[u8]) -> Self {
+        let head_end = raw.windows(4).position(|window| window == b"\r\n\r\n").unwrap_or_else(|| panic!("response has no head: {:?}", String::from_utf8_lossy(raw)));
+        let head = std::str::from_utf8(&raw[..head_end]).expect("response head isn't UTF-8");
+        let body = raw[head_end + 4..].to_vec();
+
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().expect("response has no status line");
+        let mut parts = status_line.splitn(3, ' ');
+        assert!(parts.next().is_some_and(|version| version.starts_with("HTTP/1.")), "invalid status line {status_line:?}");
+        let status = parts.next().and_then(|status| status.parse().ok()).unwrap_or_else(|| panic!("invalid status line {status_line:?}"));
+        let headers: Vec<(String, String)> = lines.map(|line| {
+            let (name, value) = line.split_once(": ").unwrap_or_else(|| panic!("invalid header line {line:?}"));
+            assert!(!name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'), "invalid header name {name:?}");
+            (name.to_string(), value.to_string())
+        }).collect();
+
+        let response = Response { status, headers, body };
+        if let Some(length) = response.header("content-length") {
+            assert_eq!(length.parse::<usize>().ok(), Some(response.body.len()), "Content-Length doesn't match the body");
+        }
+        response
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
@@ -1,23 +1,164 @@
-use anyhow::anyhow;
-use tokio::{fs::{read_link, remove_file, symlink}, io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, process::Command};
+use std::{collections::hash_map::RandomState, ffi::CString, hash::{BuildHasher, Hasher}, future::Future, io, net::IpAddr, os::unix::{ffi::OsStrExt, fs::PermissionsExt}, path::Path, pin::Pin, task::{Context, Poll}, time::Duration};
+use anyhow::{anyhow, bail};
+use tokio::{fs::{read_link, remove_file, rename, symlink, symlink_metadata}, io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf}, net::{TcpStream, UnixStream}, process::Command, task::JoinSet, time::timeout};
+use crate::{Config, HealthCheckMode, SiteConfig};
 
-pub async fn is_healthy(port: u16) -> bool {
-    async fn is_healthy_inner(port: u16) -> anyhow::Result<()> {
-        let mut stream = TcpStream::connect(format!("127.0.0.1:{port}")).await?;
+/// A connection to the upstream service, either over TCP or a unix socket
+pub enum UpstreamStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    /// Connects to the upstream, giving up after `upstream_connect_timeout_ms`
+    pub async fn connect(site_config: &SiteConfig) -> io::Result<Self> {
+        let connect = async {
+            match (&site_config.upstream_socket, site_config.port) {
+                (Some(path), _) => UnixStream::connect(path).await.map(UpstreamStream::Unix),
+                (None, Some(port)) => connect_tcp(site_config, port).await.map(UpstreamStream::Tcp),
+                (None, None) => Err(io::Error::new(io::ErrorKind::InvalidInput, "site has neither a port nor an upstream socket")),
+            }
+        };
+        with_connect_timeout(site_config, connect).await
+    }
+}
+
+async fn with_connect_timeout<T>(site_config: &SiteConfig, connect: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    let duration = Duration::from_millis(site_config.upstream_connect_timeout_ms.0);
+    match timeout(duration, connect).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connection to upstream timed out")),
+    }
+}
+
+async fn connect_tcp(site_config: &SiteConfig, port: u16) -> io::Result<TcpStream> {
+    TcpStream::connect((site_config.upstream_host(), port)).await
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Limit on the response read when looking for an expected string
+const PROBE_MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+async fn probe(mut stream: UpstreamStream, mode: HealthCheckMode, expect_body_contains: Option<&str>) -> anyhow::Result<()> {
+    if mode == HealthCheckMode::Connect {
+        return Ok(());
+    }
+
+    let Some(expected) = expect_body_contains else {
         stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
         let mut buf = [0; 1];
         let bytes = stream.read(&mut buf).await?;
         if bytes == 0 {
             return Err(anyhow!("No response"));
         }
+        return Ok(());
+    };
+
+    // Read the response until the expected string shows up
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+    let mut response = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let bytes = stream.read(&mut buf).await?;
+        if bytes == 0 {
+            return Err(anyhow!("Response doesn't contain {expected:?}"));
+        }
+        response.extend_from_slice(&buf[..bytes]);
+        if response.windows(expected.len()).any(|window| window == expected.as_bytes()) {
+            return Ok(());
+        }
+        if response.len() > PROBE_MAX_RESPONSE_BYTES {
+            return Err(anyhow!("Response doesn't contain {expected:?} in its first {PROBE_MAX_RESPONSE_BYTES} bytes"));
+        }
+    }
+}
+
+pub async fn is_healthy(site_config: &SiteConfig) -> bool {
+    let health_check_timeout = Duration::from_millis(site_config.health_check_timeout_ms.0);
+    if let Some(command) = &site_config.health_check_command {
+        return matches!(timeout(health_check_timeout, run_command(command)).await, Ok(Ok(())));
+    }
+
+    async fn is_healthy_inner(site_config: &SiteConfig) -> anyhow::Result<()> {
+        let stream = UpstreamStream::connect(site_config).await?;
+        probe(stream, site_config.health_check_mode, site_config.health_check_expect_body_contains.as_deref()).await
+    }
+
+    // An upstream can accept connections without ever answering
+    matches!(timeout(health_check_timeout, is_healthy_inner(site_config)).await, Ok(Ok(())))
+}
+
+/// Probes the upstream and the readiness ports concurrently.
+/// The site is ready when at least `readiness_quorum` of them respond.
+pub async fn is_ready(site_config: &'static SiteConfig) -> bool {
+    if site_config.readiness_ports.is_empty() {
+        return is_healthy(site_config).await;
+    }
+
+    let mut probes = JoinSet::new();
+    probes.spawn(is_healthy(site_config));
+    for port in &site_config.readiness_ports {
+        probes.spawn(async move {
+            let probe_port = async {
+                let stream = with_connect_timeout(site_config, connect_tcp(site_config, *port)).await?;
+                probe(UpstreamStream::Tcp(stream), site_config.health_check_mode, None).await
+            };
+            matches!(timeout(Duration::from_millis(site_config.health_check_timeout_ms.0), probe_port).await, Ok(Ok(())))
+        });
+    }
 
-        Ok(())
-    } 
+    let quorum = site_config.readiness_quorum();
+    let mut ready = 0;
+    while let Some(result) = probes.join_next().await {
+        if result.unwrap_or(false) {
+            ready += 1;
+            if ready >= quorum {
+                return true;
+            }
+        }
+    }
 
-    is_healthy_inner(port).await.is_ok()
+    false
 }
 
 pub async fn checking_symlink(original: &str, link: &str) -> anyhow::Result<bool> {
+    // Never delete something that isn't a symlink, as it could be a hand-written nginx config
+    let metadata = symlink_metadata(link).await.map_err(|e| anyhow!("could not stat {link}: {e}"))?;
+    if !metadata.file_type().is_symlink() {
+        return Err(anyhow!("{link} is not a symlink, refusing to replace it"));
+    }
+
     let previous_link = read_link(link).await?;
     let expected_link = &original;
 
@@ -25,28 +166,261 @@ pub async fn checking_symlink(original: &str, link: &str) -> anyhow::Result<bool
         return Ok(false);
     }
 
-    // Replace nginx config with hibernator config
-    remove_file(link).await.map_err(|e| anyhow!("could not remove previous symlink: {e}"))?;
-    symlink(original, link).await.map_err(|e| anyhow!("could not create symlink: {e}"))?;
+    // Create the new symlink next to the old one and rename it over it, so that the link always exists.
+    // The temporary name starts with a dot, which keeps it out of nginx's `include sites-enabled/*`.
+    let link_path = Path::new(link);
+    let file_name = link_path.file_name().ok_or(anyhow!("{link} has no file name"))?.to_string_lossy();
+    let temp_link = link_path.with_file_name(format!(".{file_name}.hibernator-tmp"));
+    if symlink_metadata(&temp_link).await.is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        // Left over by an interrupted swap
+        remove_file(&temp_link).await.map_err(|e| anyhow!("could not remove leftover symlink {}: {e}", temp_link.display()))?;
+    }
+    symlink(original, &temp_link).await.map_err(|e| anyhow!("could not create symlink: {e}"))?;
+    if let Err(e) = rename(&temp_link, link).await {
+        let _ = remove_file(&temp_link).await;
+        return Err(anyhow!("could not replace previous symlink: {e}"));
+    }
     Ok(true)
 }
 
+/// Whether a program can be run, either from its path or by looking it up in PATH.
+fn program_exists(program: &str) -> bool {
+    fn is_executable(path: &Path) -> bool {
+        path.metadata().is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    }
+
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+    std::env::var_os("PATH").is_some_and(|paths| std::env::split_paths(&paths).any(|dir| is_executable(&dir.join(program))))
+}
+
+/// Makes sure the programs used to start and stop services are installed.
+pub fn check_programs(config: &Config) -> anyhow::Result<()> {
+    for site_config in &config.sites {
+        let argv = site_config.systemctl_argv("start");
+        if !program_exists(argv[0]) {
+            bail!(
+                "Site {} needs {} to start and stop its service, but it could not be found. Install it or change systemctl_prefix",
+                site_config.name, argv[0]
+            );
+        }
+
+        for (argv, option) in [(site_config.nginx_test_argv(config), "nginx_test_command"), (site_config.nginx_reload_argv(config), "nginx_reload_command")] {
+            if !program_exists(argv[0]) {
+                bail!("Site {} needs {} to switch its nginx config, but it could not be found. Install it or change {option}", site_config.name, argv[0]);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks what config validation can't: that the services exist and that nginx configs can be switched.
+pub async fn check_environment(config: &Config) -> anyhow::Result<()> {
+    check_programs(config)?;
+
+    for site_config in &config.sites {
+        run_program(&site_config.systemctl_argv("cat"))
+            .await
+            .map_err(|e| anyhow!("Site {} service {} could not be found: {e}", site_config.name, site_config.service_name))?;
+
+        let enabled_config = site_config.nginx_enabled_config();
+        let enabled_dir = Path::new(&enabled_config).parent().unwrap_or(Path::new("."));
+        let enabled_dir = CString::new(enabled_dir.as_os_str().as_bytes())?;
+        if unsafe { libc::access(enabled_dir.as_ptr(), libc::W_OK) } != 0 {
+            bail!("Site {} nginx config {enabled_config} cannot be switched: its directory is not writable", site_config.name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a shell command. Only meant for commands the user provided as such.
 pub async fn run_command(command: &str) -> anyhow::Result<()> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
+    run_command_output(command).await.map(|_| ())
+}
+
+/// Runs a shell command and returns what it printed on its standard output.
+pub async fn run_command_output(command: &str) -> anyhow::Result<String> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    run(cmd, command).await
+}
+
+/// Runs a program with its arguments, without going through a shell.
+pub async fn run_program(argv: &[&str]) -> anyhow::Result<()> {
+    let Some((program, args)) = argv.split_first() else {
+        bail!("no program to run");
+    };
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    run(cmd, &argv.join(" ")).await.map(|_| ())
+}
+
+async fn run(mut cmd: Command, description: &str) -> anyhow::Result<String> {
+    // Commands given up on with a timeout shouldn't keep running
+    cmd.kill_on_drop(true);
+    let output = cmd
         .output()
         .await
         .map_err(|e| anyhow!("could not run command: {e}"))?;
     if !output.status.success() {
         let stdout = String::from_utf8_lossy(&output.stdout);
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("command failed: {command} {stdout} {stderr}"));
+        return Err(anyhow!("command failed: {description} {stdout} {stderr}"));
     }
 
-    Ok(())
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A random number, good enough to spread events over time.
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
 }
 
 pub fn now() -> u64 {
     chrono::Utc::now().timestamp() as u64
 }
+
+/// Truncates a string to at most `max_len` bytes, without splitting a character
+pub fn truncate_str(s: &mut String, max_len: usize) {
+    if s.len() <= max_len {
+        return;
+    }
+
+    let mut len = max_len;
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    s.truncate(len);
+}
+
+/// Parses a client IP, unwrapping IPv4-mapped IPv6 addresses so they match IPv4 ranges
+pub fn parse_ip(s: &str) -> Option<IpAddr> {
+    s.trim().parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn checking_symlink_never_replaces_real_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let enabled = dir.path().join("enabled").to_str().unwrap().to_string();
+        std::fs::write(&enabled, "hand-written config").unwrap();
+
+        let error = checking_symlink("/etc/nginx/sites-available/site", &enabled).await.unwrap_err();
+        assert!(error.to_string().contains("is not a symlink"), "{error}");
+        assert_eq!(std::fs::read_to_string(&enabled).unwrap(), "hand-written config");
+    }
+
+    #[tokio::test]
+    async fn checking_symlink_swaps_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let enabled = dir.path().join("enabled").to_str().unwrap().to_string();
+        std::os::unix::fs::symlink("/old", &enabled).unwrap();
+
+        assert!(checking_symlink("/new", &enabled).await.unwrap());
+        assert_eq!(std::fs::read_link(&enabled).unwrap().to_str(), Some("/new"));
+        assert!(!checking_symlink("/new", &enabled).await.unwrap());
+    }
+
+    #[test]
+    fn truncate_str_keeps_characters_whole() {
+        let mut s = String::from("héllo");
+        truncate_str(&mut s, 2);
+        assert_eq!(s, "h");
+
+        let mut s = String::from("héllo");
+        truncate_str(&mut s, 3);
+        assert_eq!(s, "hé");
+
+        let mut s = String::from("a🦀b");
+        truncate_str(&mut s, 4);
+        assert_eq!(s, "a");
+
+        let mut s = String::from("short");
+        truncate_str(&mut s, 10);
+        assert_eq!(s, "short");
+    }
+
+    #[tokio::test]
+    async fn readiness_quorum_waits_for_enough_ports() {
+        let env = TestEnv::new();
+        let (port, readiness_port) = (free_port(), free_port());
+        let site = env.site("quorum", port, &format!("readiness_ports = [{readiness_port}]\nreadiness_quorum = 2"));
+        let site_config = &env.load("", vec![site]).sites[0];
+
+        let _upstream = tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        assert!(!is_ready(site_config).await);
+
+        let _readiness = tokio::net::TcpListener::bind(("127.0.0.1", readiness_port)).await.unwrap();
+        assert!(is_ready(site_config).await);
+    }
+
+    #[tokio::test]
+    async fn health_checks_can_require_a_body_marker() {
+        let env = TestEnv::new();
+        let (ready_port, _) = upstream(ok_response("status: ready")).await;
+        let (booting_port, _) = upstream(ok_response("status: booting")).await;
+        let marker = "health_check_mode = \"http\"\nhealth_check_expect_body_contains = \"ready\"";
+        let config = env.load("", vec![env.site("marked", ready_port, marker), env.site("unmarked", booting_port, marker)]);
+
+        assert!(is_healthy(&config.sites[0]).await);
+        assert!(!is_healthy(&config.sites[1]).await);
+    }
+
+    #[tokio::test]
+    async fn hanging_health_check_commands_time_out() {
+        let env = TestEnv::new();
+        let site = env.site("hanging", free_port(), "health_check_command = \"sleep 10\"\nhealth_check_timeout_ms = 100");
+        let config = env.load("", vec![site]);
+
+        let healthy = timeout(Duration::from_secs(2), is_healthy(&config.sites[0])).await.expect("the health check wasn't given up on");
+        assert!(!healthy);
+    }
+
+    #[tokio::test]
+    async fn silent_upstreams_are_unhealthy() {
+        let env = TestEnv::new();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let site = env.site("silent", port, "health_check_mode = \"http\"\nhealth_check_timeout_ms = 100");
+        let config = env.load("", vec![site]);
+
+        // Connections are accepted but never answered
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let healthy = timeout(Duration::from_secs(2), is_healthy(&config.sites[0])).await.expect("the health check wasn't given up on");
+        assert!(!healthy);
+    }
+
+    #[tokio::test]
+    async fn stalled_responses_time_out_when_looking_for_the_body_marker() {
+        let env = TestEnv::new();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let marker = "health_check_mode = \"http\"\nhealth_check_expect_body_contains = \"ready\"\nhealth_check_timeout_ms = 100";
+        let config = env.load("", vec![env.site("stalled", port, marker)]);
+
+        // The response starts but never gets to the marker
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n").await;
+                streams.push(stream);
+            }
+        });
+
+        let healthy = timeout(Duration::from_secs(2), is_healthy(&config.sites[0])).await.expect("the health check wasn't given up on");
+        assert!(!healthy);
+    }
+}
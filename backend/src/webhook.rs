@@ -0,0 +1,67 @@
+//! Notifications sent to `state_change_webhook` when sites change state.
+
+use std::{sync::LazyLock, time::Duration};
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::Serialize;
+use tokio::{spawn, time::sleep};
+use crate::controller::SiteState;
+
+const WEBHOOK_ATTEMPTS: u32 = 3;
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(2);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build().expect("could not create webhook client")
+});
+
+#[derive(Serialize)]
+struct StateChange<'a> {
+    service: &'a str,
+    old_state: &'static str,
+    new_state: &'static str,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    timestamp: DateTime<Utc>,
+}
+
+async fn post(url: &str, body: &str) -> anyhow::Result<()> {
+    CLIENT
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Posts the state change to the webhook in the background, retrying a few times on failure.
+pub fn notify_state_change(url: &'static str, service: &str, old_state: SiteState, new_state: SiteState) {
+    let payload = StateChange {
+        service,
+        old_state: old_state.as_str(),
+        new_state: new_state.as_str(),
+        timestamp: Utc::now(),
+    };
+    let body = match serde_json::to_string(&payload) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Could not serialize state change of {service}: {e}");
+            return;
+        }
+    };
+    let service = service.to_string();
+
+    spawn(async move {
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            match post(url, &body).await {
+                Ok(()) => return,
+                Err(e) => warn!("Could not send state change of {service} to webhook (attempt {attempt}/{WEBHOOK_ATTEMPTS}): {e}"),
+            }
+            if attempt < WEBHOOK_ATTEMPTS {
+                sleep(WEBHOOK_RETRY_DELAY).await;
+            }
+        }
+        error!("Giving up on sending state change of {service} to webhook");
+    });
+}
@@ -8,13 +8,38 @@ export type ConnectionResult =
   | 'ProxyFailed'
   | 'ProxyTimeout'
   | 'ApiHandled'
+  | 'BodyTooLarge'
+  | 'InvalidContentLength'
 
-export type ServiceState = 'unknown' | 'down' | 'up' | 'starting'
+export type ServiceState = 'unknown' | 'down' | 'up' | 'starting' | 'stopping'
 
 export interface ServiceInfo {
   name: string
-  state: ServiceState
+  state: ServiceState | 'disabled'
+  enabled: boolean
   last_changed: number
+  failed_starts: number
+  start_backoff_until: number | null
+  nginx_error: string | null
+  parse_error: string | null
+  access_log_error: string | null // Set while the access log is missing or unreadable
+  progress_done_ms: number | null
+  progress_total_ms: number | null
+  total_wakes: number
+  total_hibernations: number
+  last_wake: number | null
+}
+
+export interface FleetStatus {
+  sites: number
+  up: number
+  down: number
+  starting: number
+  stopping: number
+  unknown: number
+  disabled: number
+  memory_saved_mb: number
+  oldest_sleeping: { name: string; since: number } | null
 }
 
 export interface ServiceConfig {
@@ -29,6 +54,11 @@ export interface ConnectionMetadata {
   real_ip?: string
   method: string
   url: string
+  status: number | null
+  cold: boolean
+  wake_wait_ms: number | null
+  upstream_ms: number | null
+  country: string | null
 }
 
 export interface HistoryEntry {
@@ -40,6 +70,11 @@ export interface HistoryEntry {
   real_ip?: string
   method: string
   url: string
+  status: number | null
+  cold: boolean
+  wake_wait_ms: number | null
+  upstream_ms: number | null
+  country: string | null
 }
 
 export interface StateHistoryEntry {
@@ -47,6 +82,7 @@ export interface StateHistoryEntry {
   end_time: number
   service: string
   state: ServiceState
+  trigger_source: 'request' | 'api' | null
 }
 
 export interface ServiceMetrics {
@@ -55,4 +91,26 @@ export interface ServiceMetrics {
   total_hibernations: number
   start_times_histogram: number[] // Buckets: [0-1s, 1-5s, 5-10s, 10-30s, 30s+]
   start_duration_estimate_ms: number | null
+  cold_request_ratio: number | null
+  state_durations: Record<string, number> // Seconds spent in each state
+  result_counts: Partial<Record<ConnectionResult, number>> // Number of requests by result
+  wake_stats: WakeStats // Since the hibernator started, regardless of the window
+}
+
+export interface WakeStats {
+  current_waiters: number // Requests currently waiting for the site to start
+  last_wake_waiters: number // Requests waiting when the site last became ready
+  max_wake_waiters: number
+  current_cycle_requests: number // Requests proxied since the last wake
+  last_cycle_requests: number // Requests proxied during the previous wake cycle
+}
+
+export interface StartDurations {
+  name: string
+  samples_ms: number[] // Newest first
+  count: number
+  min_ms: number | null
+  p50_ms: number | null
+  p95_ms: number | null
+  max_ms: number | null
 }